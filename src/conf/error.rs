@@ -0,0 +1,280 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    // serde_yaml already renders the location into the message
+    #[error("invalid yaml: {message}")]
+    Parse {
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+
+    #[error("{location}: list must not be empty")]
+    EmptyServers { location: String },
+
+    #[error("servers[{index}]: server_name `{name}` is already used by servers[{first}]")]
+    DuplicateServerName {
+        name: String,
+        index: usize,
+        first: usize,
+    },
+
+    #[error("upstreams[{index}]: upstream `{name}` is already defined by upstreams[{first}]")]
+    DuplicateUpstream {
+        name: String,
+        index: usize,
+        first: usize,
+    },
+
+    #[error("servers[{index}] ({server}): upstream `{upstream}` not found")]
+    UpstreamNotFound {
+        index: usize,
+        server: String,
+        upstream: String,
+    },
+
+    #[error("{location}: invalid address `{addr}`: {reason}")]
+    InvalidAddress {
+        location: String,
+        addr: String,
+        reason: String,
+    },
+
+    #[error("{location}: {kind} file does not exist: {path}")]
+    MissingFile {
+        location: String,
+        kind: &'static str,
+        path: String,
+    },
+
+    #[error("{location}: {message}")]
+    Invalid { location: String, message: String },
+
+    /// An error with the position of the yaml node it is about, 1-based like serde_yaml's.
+    #[error("{}:{line}:{column}: {error}", file.display())]
+    At {
+        file: PathBuf,
+        line: usize,
+        column: usize,
+        error: Box<ConfigError>,
+    },
+}
+
+impl ConfigError {
+    // the yaml node the error is about, as in the locations of the messages
+    fn path(&self) -> Option<String> {
+        match self {
+            Self::EmptyServers { location }
+            | Self::InvalidAddress { location, .. }
+            | Self::MissingFile { location, .. }
+            | Self::Invalid { location, .. } => Some(location.clone()),
+            Self::DuplicateServerName { index, .. } => {
+                Some(format!("servers[{}].server_name", index))
+            }
+            Self::DuplicateUpstream { index, .. } => Some(format!("upstreams[{}].name", index)),
+            Self::UpstreamNotFound { index, .. } => Some(format!("servers[{}].upstream", index)),
+            Self::Io { .. } | Self::Parse { .. } | Self::At { .. } => None,
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for ConfigError {
+    fn from(e: serde_yaml::Error) -> Self {
+        let location = e.location();
+        Self::Parse {
+            line: location.as_ref().map(|l| l.line()),
+            column: location.as_ref().map(|l| l.column()),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// All the problems found while resolving a config, so they can be fixed in one go.
+#[derive(Debug, Default)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl ConfigErrors {
+    pub fn push(&mut self, error: ConfigError) {
+        self.0.push(error);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ConfigError> {
+        self.0.iter()
+    }
+
+    /// Attach to each error the position in `source`, the content of `file`, of the node it
+    /// is about. A node the config leaves out is located by its closest parent.
+    pub fn locate(self, file: &Path, source: &str) -> Self {
+        let errors = self.0.into_iter().map(|error| {
+            let position = error
+                .path()
+                .and_then(|path| parse_path(&path, source))
+                .and_then(|path| {
+                    (1..=path.len())
+                        .rev()
+                        .find_map(|len| position(source, &path[..len]))
+                });
+            match position {
+                Some((line, column)) => ConfigError::At {
+                    file: file.to_path_buf(),
+                    line,
+                    column,
+                    error: Box::new(error),
+                },
+                None => error,
+            }
+        });
+        Self(errors.collect())
+    }
+
+    /// Collect the errors of a nested resolution, returning its value if it succeeded.
+    pub fn absorb<T>(&mut self, result: Result<T, ConfigErrors>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(errors) => {
+                self.0.extend(errors.0);
+                None
+            }
+        }
+    }
+}
+
+impl From<ConfigError> for ConfigErrors {
+    fn from(error: ConfigError) -> Self {
+        Self(vec![error])
+    }
+}
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.as_slice() {
+            [error] => write!(f, "{}", error),
+            errors => {
+                write!(f, "found {} config errors:", errors.len())?;
+                for error in errors {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+// `servers[1].locations[0].path` into its segments; the locations of upstream errors start
+// with the name of the upstream, `upstream \`web\`.servers`, which is looked up in `source`
+fn parse_path(path: &str, source: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+    if let Some(named) = rest.strip_prefix("upstream `") {
+        let (name, after) = named.split_once('`')?;
+        let config: serde_yaml::Value = serde_yaml::from_str(source).ok()?;
+        let index = config
+            .get("upstreams")?
+            .as_sequence()?
+            .iter()
+            .position(|upstream| upstream.get("name").and_then(|n| n.as_str()) == Some(name))?;
+        segments.push(Segment::Key("upstreams".to_string()));
+        segments.push(Segment::Index(index));
+        rest = after.strip_prefix('.').unwrap_or(after);
+    }
+    for part in rest.split('.').filter(|part| !part.is_empty()) {
+        let (key, mut indices) = match part.find('[') {
+            Some(i) => part.split_at(i),
+            None => (part, ""),
+        };
+        segments.push(Segment::Key(key.to_string()));
+        while let Some(index) = indices.strip_prefix('[') {
+            let (index, after) = index.split_once(']')?;
+            segments.push(Segment::Index(index.parse().ok()?));
+            indices = after;
+        }
+    }
+    Some(segments)
+}
+
+// the line and column of the node at `path`, `None` if there is none; serde_yaml only tells
+// positions with errors, so the node is walked to and failed on
+fn position(source: &str, path: &[Segment]) -> Option<(usize, usize)> {
+    let deserializer = serde_yaml::Deserializer::from_str(source);
+    match Locate(path).deserialize(deserializer) {
+        Ok(()) => None,
+        Err(e) => e.location().map(|l| (l.line(), l.column())),
+    }
+}
+
+struct Locate<'a>(&'a [Segment]);
+
+impl<'de> DeserializeSeed<'de> for Locate<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        // a visitor that takes nothing fails on the node itself
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Locate<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the located node")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let [Segment::Key(key), rest @ ..] = self.0 else {
+            return Err(de::Error::custom("found"));
+        };
+        while let Some(name) = map.next_key::<String>()? {
+            if name == *key {
+                map.next_value_seed(Locate(rest))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let [Segment::Index(index), rest @ ..] = self.0 else {
+            return Err(de::Error::custom("found"));
+        };
+        let mut i = 0;
+        loop {
+            let found = if i == *index {
+                seq.next_element_seed(Locate(rest))?
+            } else {
+                seq.next_element::<IgnoredAny>()?.map(|_| ())
+            };
+            if found.is_none() {
+                return Ok(());
+            }
+            i += 1;
+        }
+    }
+}
@@ -1,23 +1,29 @@
+mod error;
 mod raw;
 mod resolved;
 
 use std::{path::Path, sync::Arc};
 
+use anyhow::Context;
 use arc_swap::ArcSwap;
+pub use error::*;
 pub use raw::*;
 pub use resolved::*;
 
-pub struct ProxyConfig(ArcSwap<SimpleProxyConfigResolved>);
+#[derive(Clone)]
+pub struct ProxyConfig(Arc<ArcSwap<SimpleProxyConfigResolved>>);
 
 impl ProxyConfig {
     pub fn new(config: SimpleProxyConfigResolved) -> Self {
-        let config = ArcSwap::new(Arc::new(config));
-        Self(config)
+        let config = ArcSwap::from_pointee(config);
+        Self(Arc::new(config))
     }
 
     pub fn load(file: impl AsRef<Path>) -> anyhow::Result<Self> {
-        let config = SimpleProxyConfig::new(file);
-        Ok(Self::new(config.try_into()?))
+        let file = file.as_ref();
+        let config = SimpleProxyConfigResolved::load(file)
+            .with_context(|| format!("failed to load config {}", file.display()))?;
+        Ok(Self::new(config))
     }
 
     pub fn update(&self, config: SimpleProxyConfigResolved) {
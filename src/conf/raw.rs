@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{collections::BTreeMap, path::Path, str::FromStr, time::Duration};
+
+use super::ConfigError;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SimpleProxyConfig {
@@ -10,17 +12,281 @@ pub struct SimpleProxyConfig {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct GlobalConfig {
-    pub port: u16,
+    /// a single listener on all IPv4 interfaces, with TLS if `tls` is set; `listeners`
+    /// replaces it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls: Option<TlsConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<ListenerConfig>,
+
+    /// the client address of requests coming through other proxies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub real_ip: Option<RealIpConfig>,
+}
+
+impl GlobalConfig {
+    /// Names of the listeners, what servers refer to them by.
+    pub fn listener_names(&self) -> Vec<String> {
+        match self.port {
+            Some(port) if self.listeners.is_empty() => vec![format!("0.0.0.0:{}", port)],
+            _ => self.listeners.iter().map(ListenerConfig::name).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct ListenerConfig {
+    /// `address:port` by default, e.g. `0.0.0.0:80` or `[::]:443`, the address for unix
+    /// sockets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// IPv4 or IPv6 address, `0.0.0.0` by default. `::` or `[::]` accepts IPv4 too unless
+    /// `ipv6_only` is set. `unix:/path/to.sock` listens on a unix domain socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+
+    /// required unless listening on a unix socket
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// octal mode of a unix socket file, e.g. `660`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+
+    /// terminate TLS with the certificates of `global.tls`, off by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+
+    /// h2 in ALPN with TLS (on by default), h2 with prior knowledge without (off by default)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http2: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ipv6_only: Option<bool>,
+
+    /// whether connections start with a PROXY protocol header, `none` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolMode>,
+
+    /// answer every request with a redirect to its `https://` URL, only without `tls`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to_https: Option<RedirectToHttpsConfig>,
+}
+
+/// PROXY protocol (v1 or v2) on the connections of a listener.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolMode {
+    #[default]
+    None,
+    /// the client address is taken from a header if the connection starts with one
+    Optional,
+    Required,
+}
+
+/// Version of the PROXY protocol header sent to the servers of an upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Where the address of a client behind other proxies is taken from. The header is only
+/// believed on connections from a trusted proxy.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RealIpConfig {
+    /// addresses and CIDRs of the proxies, e.g. `10.0.0.0/8` or `2001:db8::1`
+    pub trusted: Vec<String>,
+
+    /// `x-forwarded-for` by default, `forwarded` or any header listing addresses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub header: Option<String>,
+
+    /// skip the trusted addresses at the end of the list instead of taking the last one,
+    /// `false` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recursive: Option<bool>,
+}
+
+/// Headers added to requests to the upstream, each on by default except `forwarded`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ForwardedHeadersConfig {
+    /// `X-Forwarded-For`, `append` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_forwarded_for: Option<ForwardedMode>,
+
+    /// RFC 7239 `Forwarded`, `off` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded: Option<ForwardedMode>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_forwarded_proto: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_forwarded_host: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_forwarded_port: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x_real_ip: Option<bool>,
+}
+
+/// How a header listing the proxies a request went through is passed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedMode {
+    /// add the client to the list the request came with
+    Append,
+    /// drop the list the request came with, only the client is passed on
+    Replace,
+    /// pass on the header as received
+    Off,
+}
+
+/// `true`, or the port and status of the redirect.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum RedirectToHttpsConfig {
+    Enabled(bool),
+    Detailed {
+        /// of the `https://` URL, 443 by default
+        #[serde(skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        /// 301 by default, 308 keeps the method and body of the request
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+    },
+}
+
+/// `Strict-Transport-Security` added to responses on TLS connections.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HstsConfig {
+    /// one year by default
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_age: Option<Duration>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_subdomains: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preload: Option<bool>,
+}
+
+impl ListenerConfig {
+    pub fn name(&self) -> String {
+        match (&self.name, self.address.as_deref().unwrap_or("0.0.0.0")) {
+            (Some(name), _) => name.clone(),
+            (None, address) if address.starts_with("unix:") => address.to_string(),
+            (None, address) if address.contains(':') && !address.starts_with('[') => {
+                format!("[{}]:{}", address, self.port.unwrap_or_default())
+            }
+            (None, address) => format!("{}:{}", address, self.port.unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct TlsConfig {
     pub cert: String,
     pub key: String,
     pub ca: Option<String>,
+
+    /// whether clients present a certificate in the handshake, `none` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuth>,
+
+    /// CA bundle client certificates are verified against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<String>,
+
+    /// PEM file of revoked client certificates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crl: Option<String>,
+
+    /// request headers the verified client identity is forwarded to upstreams in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_identity_headers: Option<ClientIdentityHeadersConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<TlsProfile>,
+
+    /// `"1.2"` or `"1.3"`, only with the `custom` profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_version: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_version: Option<String>,
+
+    /// cipher suites by their IANA names, e.g. `TLS13_AES_128_GCM_SHA256`, only with the
+    /// `custom` profile
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ciphers: Vec<String>,
+
+    /// protocols offered to clients in ALPN, `[h2, http/1.1]` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn: Option<Vec<String>>,
+
+    /// stateless resumption with session tickets, off by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_tickets: Option<bool>,
+
+    /// sessions kept for stateful resumption, 0 disables it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_cache_size: Option<usize>,
+
+    /// for the servers without an `hsts` block of their own
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsConfig>,
+}
+
+/// Protocol versions and cipher suites offered to clients, after Mozilla's TLS guidelines.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsProfile {
+    /// TLS 1.3 only
+    Modern,
+    /// TLS 1.2 and 1.3 with forward secret AEAD cipher suites
+    #[default]
+    Intermediate,
+    /// `min_version`, `max_version` and `ciphers` as configured
+    Custom,
+}
+
+/// Client certificate authentication of downstream connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// a certificate is requested and verified if presented
+    Optional,
+    Required,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ClientIdentityHeadersConfig {
+    /// distinguished name of the certificate subject
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    /// subject alternative names, comma separated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub san: Option<String>,
+
+    /// hex SHA-256 of the DER certificate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,20 +296,433 @@ pub struct ServerConfig {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tls: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_server: Option<bool>,
+
+    /// overrides `global.tls.client_auth` for requests to this server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuth>,
+
+    /// certificate and key served to clients asking for this server by SNI, the ones of
+    /// `global.tls` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+
+    /// names of the listeners the server is reachable on, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<String>,
+
+    /// redirect requests that do not come over TLS to their `https://` URL
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to_https: Option<RedirectToHttpsConfig>,
+
+    /// overrides `global.tls.hsts`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsConfig>,
+
+    /// headers telling the upstream about the client and the request it sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forwarded_headers: Option<ForwardedHeadersConfig>,
+
+    /// changes to the headers of requests to the upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_headers: Option<HeaderRewriteConfig>,
+
+    /// changes to the headers of responses to the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<HeaderRewriteConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<LocationConfig>,
+
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matches: Option<MatchConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LocationConfig {
+    /// `/static/` for a prefix, `= /health` for an exact path and `~ ^/v\d+/` for a regex
+    pub path: String,
+    pub upstream: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<bool>,
+
+    /// applied after the server's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_headers: Option<HeaderRewriteConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_headers: Option<HeaderRewriteConfig>,
+
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matches: Option<MatchConfig>,
+}
+
+/// Changes to headers, made in the order `remove_matching`, `remove`, `set` and `add`. Values
+/// may contain `$client_ip`, `$host`, `$scheme`, `$request_id` and `$upstream_addr`, also
+/// written `${host}`; `$$` is a literal `$`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HeaderRewriteConfig {
+    /// header name to value, replacing any value the header has
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+
+    /// header name to value, kept along with the values the header has
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+
+    /// header names
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+
+    /// regexes of header names, matched against the lowercase name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove_matching: Vec<String>,
+}
+
+/// Request attributes a server or location is restricted to. All the given conditions must
+/// hold; a value is compared literally or, prefixed with `~`, as a regex.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct MatchConfig {
+    /// any of these methods
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub method: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub query: BTreeMap<String, String>,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cookies: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct UpstreamConfig {
     pub name: String,
-    pub servers: Vec<String>,
+    pub servers: Vec<UpstreamServerConfig>,
+
+    #[serde(default)]
+    pub algorithm: LoadBalanceAlgorithm,
+
+    /// what `ketama` and `fnv_hash` hash on: `client_ip` (default), `uri`, `path`,
+    /// `header:<name>` or `cookie:<name>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_key: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheckConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryConfig>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+
+    /// send a PROXY protocol header with the client address on each connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// establishing the TCP connection
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub connect_timeout: Option<Duration>,
+
+    /// establishing the connection including the TLS handshake
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub total_connection_timeout: Option<Duration>,
+
+    /// each read from the server
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub read_timeout: Option<Duration>,
+
+    /// each write to the server
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub write_timeout: Option<Duration>,
+
+    /// how long an idle connection is kept for reuse
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<Duration>,
+
+    /// deadline of a request across every attempt, counted from its arrival
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_timeout: Option<Duration>,
+}
+
+/// Active health check of the servers of an upstream. Durations are humantime strings
+/// such as `500ms` or `10s`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "type", default)]
+    pub kind: HealthCheckType,
+
+    /// http only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+
+    /// http only, the `Host` header of the probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    /// http only, status codes counted as healthy (default 200)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expected_status: Vec<u16>,
+
+    /// http only, a substring the response body must contain
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub interval: Option<Duration>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// consecutive successful checks to mark an unhealthy server healthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthy_threshold: Option<usize>,
+
+    /// consecutive failed checks to mark a healthy server unhealthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unhealthy_threshold: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckType {
+    #[default]
+    Tcp,
+    Http,
+}
+
+/// Passive health checking: servers failing live requests are ejected from the upstream for
+/// a while, twice as long on every ejection in a row.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct OutlierDetectionConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// 5xx responses in a row to eject a server, 0 to disable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consecutive_5xx: Option<u32>,
+
+    /// failed connects in a row to eject a server, 0 to disable
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consecutive_connect_failures: Option<u32>,
+
+    /// share of failed requests within `window` to eject a server, e.g. `0.5`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<f64>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub window: Option<Duration>,
+
+    /// requests within `window` before `error_rate` applies
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_requests: Option<u32>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub base_ejection_time: Option<Duration>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_ejection_time: Option<Duration>,
+
+    /// upper bound of servers ejected at the same time, in percent of the upstream
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_ejection_percent: Option<u8>,
+}
+
+/// How a failed request is retried on another server of the upstream.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RetryConfig {
+    /// attempts in total including the first one, 1 disables retries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<usize>,
+
+    /// what is retried: `connect_failure` (default), `timeout` and `error`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retry_on: Vec<RetryOn>,
+
+    /// response statuses retried as long as nothing was sent to the client yet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<u16>,
+
+    /// only retry idempotent methods once the request was sent, default true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idempotent_only: Option<bool>,
+
+    #[serde(
+        default,
+        with = "humantime_serde",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub per_try_timeout: Option<Duration>,
+
+    /// retries allowed on top of the upstream's requests, e.g. `0.2` for 20%
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub budget: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// the server could not be connected to, nothing was sent
+    ConnectFailure,
+    /// the server did not answer within the timeouts
+    Timeout,
+    /// the connection broke before the response started
+    Error,
+}
+
+/// TLS towards the servers of an upstream, every route to the upstream uses TLS when set.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct UpstreamTlsConfig {
+    /// server name sent in the handshake, the request's host by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+
+    /// whether the server certificate is verified, `true` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_cert: Option<bool>,
+
+    /// whether the server certificate must match the server name, `true` by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_hostname: Option<bool>,
+
+    /// CA bundle the servers are verified against instead of the platform's roots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+
+    /// certificate and key files presented to servers that require client certificates
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alpn: Option<Alpn>,
+}
+
+/// HTTP versions offered to the server in the TLS handshake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Alpn {
+    #[default]
+    H1,
+    H2,
+    /// h2 with a fallback to h1
+    H2H1,
+}
+
+/// A backend of an upstream, either `host:port` or `{ addr, weight, backup }`.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum UpstreamServerConfig {
+    Addr(String),
+    Detailed {
+        addr: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        weight: Option<usize>,
+        /// only receives traffic when no primary backend is healthy
+        #[serde(skip_serializing_if = "Option::is_none")]
+        backup: Option<bool>,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalanceAlgorithm {
+    #[default]
+    RoundRobin,
+    Random,
+    Ketama,
+    FnvHash,
+    LeastConn,
+}
+
+impl LoadBalanceAlgorithm {
+    pub fn is_hashing(&self) -> bool {
+        matches!(self, Self::Ketama | Self::FnvHash)
+    }
+}
+
+impl UpstreamServerConfig {
+    pub fn addr(&self) -> &str {
+        match self {
+            Self::Addr(addr) | Self::Detailed { addr, .. } => addr,
+        }
+    }
 }
 
 impl SimpleProxyConfig {
-    pub fn new(path: impl AsRef<Path>) -> Self {
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
         let path = path.as_ref();
-        let config = std::fs::read_to_string(path).unwrap();
-        let config: SimpleProxyConfig = serde_yaml::from_str(&config).unwrap();
-        config
+        let config = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        config.parse()
+    }
+}
+
+impl FromStr for SimpleProxyConfig {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(serde_yaml::from_str(s)?)
     }
 }
 
@@ -60,11 +739,11 @@ mod tests {
     }
 
     #[test]
-    fn test_config_parsing() {
-        let config = SimpleProxyConfig::new(get_test_config_path());
+    fn test_config_parsing() -> anyhow::Result<()> {
+        let config = SimpleProxyConfig::new(get_test_config_path())?;
 
         // Test global config
-        assert_eq!(config.global.port, 8080);
+        assert_eq!(config.global.port, Some(8080));
         assert!(config.global.tls.is_none());
 
         // Test servers
@@ -87,7 +766,11 @@ mod tests {
         let web_upstream = &config.upstreams[0];
         assert_eq!(web_upstream.name, "web_servers");
         assert_eq!(
-            web_upstream.servers,
+            web_upstream
+                .servers
+                .iter()
+                .map(|s| s.addr())
+                .collect::<Vec<_>>(),
             vec!["127.0.0.1:3001", "127.0.0.1:3002"]
         );
 
@@ -95,8 +778,27 @@ mod tests {
         let api_upstream = &config.upstreams[1];
         assert_eq!(api_upstream.name, "api_servers");
         assert_eq!(
-            api_upstream.servers,
+            api_upstream
+                .servers
+                .iter()
+                .map(|s| s.addr())
+                .collect::<Vec<_>>(),
             vec!["127.0.0.1:3003", "127.0.0.1:3004"]
         );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_parse_error_location() {
+        let yaml = "global:\n  port: 3000\nservers:\n  - server_name: [acme.com]\nupstreams: []\n";
+        let err = yaml.parse::<SimpleProxyConfig>().unwrap_err();
+        match err {
+            ConfigError::Parse { line, message, .. } => {
+                assert!(line.is_some());
+                assert!(message.contains("missing field `upstream`"));
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
     }
 }
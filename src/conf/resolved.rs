@@ -1,146 +1,2235 @@
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
+use axum::http::{HeaderName, HeaderValue, Method, Uri};
 use rand::seq::SliceRandom;
+use rustls::{
+    SupportedCipherSuite,
+    crypto::aws_lc_rs::{ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES},
+};
+use serde::{Serialize, Serializer};
 
-use super::{GlobalConfig, ServerConfig, SimpleProxyConfig, TlsConfig, UpstreamConfig};
+use super::{
+    Alpn, ClientAuth, ClientIdentityHeadersConfig, ConfigError, ConfigErrors,
+    ForwardedHeadersConfig, ForwardedMode, GlobalConfig, HeaderRewriteConfig, HealthCheckConfig,
+    HealthCheckType, HstsConfig, ListenerConfig, LoadBalanceAlgorithm, MatchConfig,
+    OutlierDetectionConfig, ProxyProtocolMode, ProxyProtocolVersion, RealIpConfig,
+    RedirectToHttpsConfig, RetryConfig, RetryOn, ServerConfig, SimpleProxyConfig, TlsConfig,
+    TlsProfile, UpstreamConfig, UpstreamServerConfig, UpstreamTlsConfig,
+};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct SimpleProxyConfigResolved {
     pub global: GlobalConfigResolved,
+    #[serde(serialize_with = "serialize_sorted")]
     pub servers: HashMap<String, ServerConfigResolved>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_server: Option<String>,
+    // server names in declaration order, regex names are matched in this order
+    #[serde(skip)]
+    pub server_names: Vec<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct GlobalConfigResolved {
-    pub port: u16,
+    pub listeners: Vec<ListenerConfigResolved>,
     pub tls: Option<TlsConfigResolved>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub real_ip: Option<RealIpResolved>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RealIpResolved {
+    pub trusted: Vec<IpCidr>,
+    /// lowercase header name
+    pub header: String,
+    pub recursive: bool,
+}
+
+/// A range of addresses, a single address with the full prefix length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+// addresses of upstream servers and listeners on unix domain sockets
+const UNIX_PREFIX: &str = "unix:";
+// sun_path holds 108 bytes on linux, including the terminating nul
+const MAX_UNIX_PATH: usize = 107;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListenerConfigResolved {
+    pub name: String,
+    pub addr: ListenerAddr,
+    /// mode of the socket file of a unix listener
+    #[serde(
+        serialize_with = "serialize_mode",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub permissions: Option<u32>,
+    pub tls: bool,
+    /// h2 in ALPN with TLS, h2 with prior knowledge without
+    pub http2: bool,
+    /// only for IPv6 addresses, `false` accepts IPv4 connections too
+    pub ipv6_only: bool,
+    pub proxy_protocol: ProxyProtocolMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to_https: Option<RedirectToHttpsResolved>,
+}
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RedirectToHttpsResolved {
+    pub port: u16,
+    pub status: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ForwardedHeadersResolved {
+    pub x_forwarded_for: ForwardedMode,
+    pub forwarded: ForwardedMode,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    pub x_forwarded_port: bool,
+    pub x_real_ip: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HstsResolved {
+    #[serde(with = "humantime_serde")]
+    pub max_age: Duration,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TlsConfigResolved {
+    pub cert: String,
+    pub key: String,
+    pub ca: Option<String>,
+    pub client_auth: ClientAuth,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crl: Option<String>,
+    pub client_identity_headers: ClientIdentityHeaders,
+    pub profile: TlsProfile,
+    pub versions: Vec<TlsVersion>,
+    pub ciphers: Vec<String>,
+    pub alpn: Vec<String>,
+    pub session_tickets: bool,
+    pub session_cache_size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsResolved>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Lowercase names of the request headers the verified client identity is forwarded in.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientIdentityHeaders {
+    pub subject: String,
+    pub san: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ServerConfigResolved {
+    pub upstream: UpstreamConfigResolved,
+    pub tls: bool,
+    /// `required` rejects requests without a verified client certificate, `none` does not
+    /// forward the client identity
+    pub client_auth: ClientAuth,
+    /// `None` to serve the certificate of `global.tls`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// names of the listeners the server is reachable on, all of them if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listeners: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_to_https: Option<RedirectToHttpsResolved>,
+    /// the server's own or the one of `global.tls`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hsts: Option<HstsResolved>,
+    pub forwarded_headers: ForwardedHeadersResolved,
+    #[serde(skip_serializing_if = "HeaderRewriteResolved::is_empty")]
+    pub request_headers: HeaderRewriteResolved,
+    #[serde(skip_serializing_if = "HeaderRewriteResolved::is_empty")]
+    pub response_headers: HeaderRewriteResolved,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<LocationConfigResolved>,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matches: Option<MatchConfigResolved>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LocationConfigResolved {
+    pub path: LocationPath,
+    pub upstream: UpstreamConfigResolved,
+    pub tls: bool,
+    /// applied after the server's
+    #[serde(skip_serializing_if = "HeaderRewriteResolved::is_empty")]
+    pub request_headers: HeaderRewriteResolved,
+    #[serde(skip_serializing_if = "HeaderRewriteResolved::is_empty")]
+    pub response_headers: HeaderRewriteResolved,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matches: Option<MatchConfigResolved>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HeaderRewriteResolved {
+    /// lowercase header names to values, whose variables are expanded per request
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub set: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub add: BTreeMap<String, String>,
+    /// lowercase header names
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+    /// regexes of lowercase header names
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub remove_matching: Vec<String>,
+}
+
+/// A header value, literal text and the variables that are expanded per request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderTemplate(pub Vec<TemplatePart>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TemplatePart {
+    Literal(String),
+    Variable(HeaderVariable),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderVariable {
+    /// see `global.real_ip`
+    ClientIp,
+    /// without the port
+    Host,
+    /// `http` or `https`
+    Scheme,
+    RequestId,
+    /// the backend the request went to
+    UpstreamAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MatchConfigResolved {
+    /// uppercase method names
+    pub methods: Vec<String>,
+    /// lowercase header names
+    pub headers: BTreeMap<String, ValueMatch>,
+    pub query: BTreeMap<String, ValueMatch>,
+    pub cookies: BTreeMap<String, ValueMatch>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueMatch {
+    Exact(String),
+    Regex(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamConfigResolved {
+    pub name: String,
+    pub servers: Vec<UpstreamServerResolved>,
+    pub algorithm: LoadBalanceAlgorithm,
+    /// only set for hashing algorithms
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash_key: Option<HashKey>,
+    pub health_check: HealthCheckResolved,
+    /// `None` if disabled
+    pub outlier_detection: Option<OutlierDetectionResolved>,
+    pub retry: RetryResolved,
+    #[serde(flatten)]
+    pub timeouts: UpstreamTimeouts,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_tls: Option<UpstreamTlsResolved>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct UpstreamTlsResolved {
+    /// `None` to send the request's host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sni: Option<String>,
+    pub verify_cert: bool,
+    pub verify_hostname: bool,
+    /// `None` to verify against the platform's roots
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<String>,
+    pub alpn: Alpn,
+}
+
+/// Timeouts of the connections to the servers of an upstream, `None` if unbounded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UpstreamTimeouts {
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub total_connection_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub read_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub write_timeout: Option<Duration>,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub idle_timeout: Option<Duration>,
+    /// deadline of a request across every attempt, counted from its arrival
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub request_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthCheckResolved {
+    #[serde(flatten)]
+    pub kind: HealthCheckKind,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    pub healthy_threshold: usize,
+    pub unhealthy_threshold: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    Tcp,
+    Http(HttpHealthCheckResolved),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HttpHealthCheckResolved {
+    pub path: String,
+    pub host: String,
+    pub expected_status: Vec<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutlierDetectionResolved {
+    /// 0 if disabled
+    pub consecutive_5xx: u32,
+    /// 0 if disabled
+    pub consecutive_connect_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<f64>,
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    pub min_requests: u32,
+    #[serde(with = "humantime_serde")]
+    pub base_ejection_time: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_ejection_time: Duration,
+    pub max_ejection_percent: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetryResolved {
+    /// in total, including the first attempt
+    pub attempts: usize,
+    pub retry_on: Vec<RetryOn>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub statuses: Vec<u16>,
+    pub idempotent_only: bool,
+    #[serde(with = "humantime_serde", skip_serializing_if = "Option::is_none")]
+    pub per_try_timeout: Option<Duration>,
+    pub budget: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UpstreamServerResolved {
+    /// `host:port` or `unix:/path/to.sock`
+    pub addr: String,
+    pub weight: usize,
+    pub backup: bool,
+}
+
+impl UpstreamServerResolved {
+    /// The socket path of a server reached over a unix domain socket.
+    pub fn unix_path(&self) -> Option<&str> {
+        self.addr.strip_prefix(UNIX_PREFIX)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    Uri,
+    Path,
+    /// lowercase header name
+    Header(String),
+    Cookie(String),
+}
+
+/// A location path matcher. An exact match wins, then the longest matching prefix, then the
+/// first matching regex in declaration order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationPath {
+    Exact(String),
+    Prefix(String),
+    Regex(String),
+}
+
+/// A parsed `server_name` entry, matched with nginx-like precedence:
+/// exact > longest leading wildcard > longest trailing wildcard > regex in declaration order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerName {
+    Exact(String),
+    /// `*.acme.com`, holds the suffix `.acme.com`
+    Suffix(String),
+    /// `www.acme.*`, holds the prefix `www.acme.`
+    Prefix(String),
+    /// `~^api-\d+\.acme\.com$`, holds the pattern without `~`
+    Regex(String),
+}
+
+impl SimpleProxyConfigResolved {
+    /// Errors about a node of the file come with its position.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigErrors> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let config: SimpleProxyConfig = source.parse()?;
+        Self::try_from(config).map_err(|errors| errors.locate(path, &source))
+    }
+
+    /// The upstreams of the servers and their locations, an upstream once per route to it.
+    pub fn upstreams(&self) -> impl Iterator<Item = &UpstreamConfigResolved> {
+        self.servers.values().flat_map(|server| {
+            std::iter::once(&server.upstream)
+                .chain(server.locations.iter().map(|location| &location.upstream))
+        })
+    }
+}
+
+impl TryFrom<SimpleProxyConfig> for SimpleProxyConfigResolved {
+    type Error = ConfigErrors;
+
+    fn try_from(config: SimpleProxyConfig) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let global = errors.absorb(GlobalConfigResolved::try_from(&config.global));
+
+        // broken upstreams are kept as `None` so that referencing them is not reported twice
+        let mut upstreams: HashMap<String, Option<UpstreamConfigResolved>> = HashMap::new();
+        let mut upstream_index: HashMap<&str, usize> = HashMap::new();
+        for (index, upstream) in config.upstreams.iter().enumerate() {
+            if let Some(&first) = upstream_index.get(upstream.name.as_str()) {
+                errors.push(ConfigError::DuplicateUpstream {
+                    name: upstream.name.clone(),
+                    index,
+                    first,
+                });
+                continue;
+            }
+            upstream_index.insert(&upstream.name, index);
+            let resolved = errors.absorb(UpstreamConfigResolved::try_from(upstream));
+            upstreams.insert(upstream.name.clone(), resolved);
+        }
+
+        if config.servers.is_empty() {
+            errors.push(ConfigError::EmptyServers {
+                location: "servers".to_string(),
+            });
+        }
+        let mut servers = HashMap::new();
+        let mut server_names = Vec::new();
+        let mut server_index: HashMap<String, usize> = HashMap::new();
+        let mut default_server: Option<(usize, String)> = None;
+        for (index, server) in config.servers.iter().enumerate() {
+            // host names are case insensitive, regexes are kept as written
+            let names: Vec<String> = server
+                .server_name
+                .iter()
+                .map(|name| {
+                    if name.starts_with('~') {
+                        name.clone()
+                    } else {
+                        name.to_ascii_lowercase()
+                    }
+                })
+                .collect();
+            if names.is_empty() {
+                errors.push(ConfigError::EmptyServers {
+                    location: format!("servers[{}].server_name", index),
+                });
+            }
+            if server.default_server.unwrap_or(false) {
+                match &default_server {
+                    Some((first, _)) => errors.push(ConfigError::Invalid {
+                        location: format!("servers[{}].default_server", index),
+                        message: format!("default server is already set by servers[{}]", first),
+                    }),
+                    None => {
+                        if let Some(name) = names.first() {
+                            default_server = Some((index, name.clone()));
+                        }
+                    }
+                }
+            }
+            for name in names.iter() {
+                if let Err(reason) = name.parse::<ServerName>() {
+                    errors.push(ConfigError::Invalid {
+                        location: format!("servers[{}].server_name", index),
+                        message: format!("invalid server name `{}`: {}", name, reason),
+                    });
+                }
+                match server_index.get(name.as_str()) {
+                    Some(&first) => errors.push(ConfigError::DuplicateServerName {
+                        name: name.clone(),
+                        index,
+                        first,
+                    }),
+                    None => {
+                        server_index.insert(name.clone(), index);
+                        server_names.push(name.clone());
+                    }
+                }
+            }
+
+            // the raw `global` so that a broken tls or listener is not reported again
+            let server_resolved = errors.absorb(ServerConfigResolved::try_from_with_upstreams(
+                server,
+                index,
+                &upstreams,
+                &config.global,
+            ));
+            if let Some(server_resolved) = server_resolved {
+                for name in names {
+                    servers
+                        .entry(name)
+                        .or_insert_with(|| server_resolved.clone());
+                }
+            }
+        }
+
+        match global {
+            Some(global) if errors.is_empty() => Ok(Self {
+                global,
+                servers,
+                default_server: default_server.map(|(_, name)| name),
+                server_names,
+            }),
+            _ => Err(errors),
+        }
+    }
+}
+
+impl TryFrom<&GlobalConfig> for GlobalConfigResolved {
+    type Error = ConfigErrors;
+
+    fn try_from(config: &GlobalConfig) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let tls = match &config.tls {
+            Some(tls) => errors.absorb(TlsConfigResolved::try_from(tls)),
+            None => None,
+        };
+        let mut invalid = |location: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: location.to_string(),
+                message: message.to_string(),
+            });
+        };
+
+        // listeners by their index in `global.listeners`
+        let mut listeners: Vec<(usize, ListenerConfigResolved)> = Vec::new();
+        match (config.port, config.listeners.is_empty()) {
+            (Some(port), true) => listeners.push((
+                0,
+                ListenerConfigResolved {
+                    name: format!("0.0.0.0:{}", port),
+                    addr: ListenerAddr::Inet(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port))),
+                    permissions: None,
+                    tls: config.tls.is_some(),
+                    http2: tls
+                        .as_ref()
+                        .is_some_and(|tls| tls.alpn.iter().any(|p| p == "h2")),
+                    ipv6_only: false,
+                    proxy_protocol: ProxyProtocolMode::None,
+                    redirect_to_https: None,
+                },
+            )),
+            (Some(_), false) => invalid(
+                "global.port",
+                "cannot be combined with listeners, add a listener for it instead",
+            ),
+            (None, true) => invalid("global", "port or listeners is required"),
+            (None, false) => {
+                for (index, listener) in config.listeners.iter().enumerate() {
+                    let resolved = errors.absorb(ListenerConfigResolved::try_from_with_index(
+                        listener,
+                        index,
+                        config.tls.as_ref(),
+                    ));
+                    listeners.extend(resolved.map(|resolved| (index, resolved)));
+                }
+            }
+        }
+        for (i, (index, listener)) in listeners.iter().enumerate() {
+            let location = format!("global.listeners[{}]", index);
+            let earlier = &listeners[..i];
+            if let Some((first, _)) = earlier.iter().find(|(_, l)| l.name == listener.name) {
+                errors.push(ConfigError::Invalid {
+                    location: format!("{}.name", location),
+                    message: format!(
+                        "duplicate listener name `{}`, first used by global.listeners[{}]",
+                        listener.name, first
+                    ),
+                });
+            }
+            if let Some((first, _)) = earlier.iter().find(|(_, l)| l.overlaps(listener)) {
+                errors.push(ConfigError::Invalid {
+                    location,
+                    message: format!(
+                        "{} overlaps with global.listeners[{}], they cannot both be bound",
+                        listener.addr, first
+                    ),
+                });
+            }
+        }
+
+        let real_ip = match &config.real_ip {
+            Some(real_ip) => errors.absorb(RealIpResolved::try_from_with_location(
+                real_ip,
+                "global.real_ip",
+            )),
+            None => None,
+        };
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            listeners: listeners
+                .into_iter()
+                .map(|(_, listener)| listener)
+                .collect(),
+            tls,
+            real_ip,
+        })
+    }
+}
+
+impl ListenerConfigResolved {
+    fn try_from_with_index(
+        config: &ListenerConfig,
+        index: usize,
+        global_tls: Option<&TlsConfig>,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("global.listeners[{}].{}", index, field),
+                message: message.to_string(),
+            });
+        };
+
+        let address = config.address.as_deref().unwrap_or("0.0.0.0");
+        let addr = match address.strip_prefix(UNIX_PREFIX) {
+            Some(path) => {
+                if let Err(reason) = validate_unix_path(path) {
+                    invalid("address", &reason);
+                }
+                if config.port.is_some() {
+                    invalid("port", "does not apply to unix sockets");
+                }
+                if config.ipv6_only.is_some() {
+                    invalid("ipv6_only", "only applies to IPv6 addresses");
+                }
+                Some(ListenerAddr::Unix(PathBuf::from(path)))
+            }
+            None => {
+                // IPv6 addresses may be written in brackets, as in URLs
+                let unbracketed = address
+                    .strip_prefix('[')
+                    .and_then(|address| address.strip_suffix(']'));
+                let ip = match unbracketed.unwrap_or(address).parse::<IpAddr>() {
+                    Ok(IpAddr::V4(_)) if unbracketed.is_some() => {
+                        invalid("address", &format!("`{}` is not an IPv6 address", address));
+                        None
+                    }
+                    Ok(ip) => Some(ip),
+                    Err(_) => {
+                        invalid("address", &format!("invalid IP address `{}`", address));
+                        None
+                    }
+                };
+                let port = match config.port {
+                    Some(0) => {
+                        invalid("port", "must not be 0");
+                        None
+                    }
+                    Some(port) => Some(port),
+                    None => {
+                        invalid("port", "is required");
+                        None
+                    }
+                };
+                if config.ipv6_only.is_some() && ip.is_some_and(|ip| ip.is_ipv4()) {
+                    invalid("ipv6_only", "only applies to IPv6 addresses");
+                }
+                if config.permissions.is_some() {
+                    invalid("permissions", "only applies to unix sockets");
+                }
+                ip.zip(port)
+                    .map(|(ip, port)| ListenerAddr::Inet(SocketAddr::new(ip, port)))
+            }
+        };
+        let permissions = match &config.permissions {
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(mode) if mode <= 0o777 => Some(mode),
+                _ => {
+                    invalid(
+                        "permissions",
+                        &format!("invalid mode `{}`, expected octal digits like 660", mode),
+                    );
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let tls = config.tls.unwrap_or(false);
+        let alpn = global_tls.map(|tls| match &tls.alpn {
+            Some(alpn) => (
+                alpn.iter().any(|p| p == "h2"),
+                alpn.iter().any(|p| p == "http/1.1"),
+            ),
+            None => (true, true),
+        });
+        let proxy_protocol = config.proxy_protocol.unwrap_or_default();
+        if matches!(addr, Some(ListenerAddr::Unix(_))) {
+            if tls {
+                invalid("tls", "is not supported on unix sockets");
+            }
+            if proxy_protocol != ProxyProtocolMode::None {
+                invalid("proxy_protocol", "is not supported on unix sockets");
+            }
+        }
+        let http2 = match (tls, alpn, config.http2) {
+            (false, _, http2) => http2.unwrap_or(false),
+            (true, None, _) => {
+                invalid("tls", "requires global.tls");
+                false
+            }
+            (true, Some((h2, _)), None) => h2,
+            (true, Some((false, _)), Some(true)) => {
+                invalid("http2", "requires h2 in global.tls.alpn");
+                false
+            }
+            (true, Some((_, false)), Some(false)) => {
+                invalid("http2", "`false` requires http/1.1 in global.tls.alpn");
+                false
+            }
+            (true, Some(_), Some(http2)) => http2,
+        };
+        let location = format!("global.listeners[{}].redirect_to_https", index);
+        let redirect_to_https = match &config.redirect_to_https {
+            Some(redirect) => errors
+                .absorb(RedirectToHttpsResolved::try_from_with_location(
+                    redirect, &location,
+                ))
+                .flatten(),
+            None => None,
+        };
+        if tls && redirect_to_https.is_some() {
+            errors.push(ConfigError::Invalid {
+                location,
+                message: "only applies to listeners without tls".to_string(),
+            });
+        }
+
+        match addr {
+            Some(addr) if errors.is_empty() => Ok(Self {
+                name: config.name(),
+                addr,
+                permissions,
+                tls,
+                http2,
+                ipv6_only: config.ipv6_only.unwrap_or(false),
+                proxy_protocol,
+                redirect_to_https,
+            }),
+            _ => Err(errors),
+        }
+    }
+
+    /// Whether the two listeners cannot be bound at the same time.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        let (a, b) = match (&self.addr, &other.addr) {
+            (ListenerAddr::Inet(a), ListenerAddr::Inet(b)) => (a, b),
+            (ListenerAddr::Unix(a), ListenerAddr::Unix(b)) => return a == b,
+            _ => return false,
+        };
+        // a wildcard address takes the port on every address of its family, a dual-stack
+        // one on both families
+        let covers = |a: &SocketAddr, a_v6_only: bool, b: &SocketAddr| {
+            a.ip() == b.ip()
+                || (a.ip().is_unspecified()
+                    && (a.is_ipv4() == b.is_ipv4() || (a.is_ipv6() && !a_v6_only)))
+        };
+        a.port() == b.port() && (covers(a, self.ipv6_only, b) || covers(b, other.ipv6_only, a))
+    }
+}
+
+impl std::fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{}", addr),
+            Self::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl Serialize for ListenerAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl RealIpResolved {
+    pub(crate) fn try_from_with_location(
+        config: &RealIpConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: String| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message,
+            })
+        };
+
+        if config.trusted.is_empty() {
+            invalid("trusted", "at least one address is required".to_string());
+        }
+        let mut trusted = Vec::new();
+        for (i, cidr) in config.trusted.iter().enumerate() {
+            match cidr.parse::<IpCidr>() {
+                Ok(cidr) => trusted.push(cidr),
+                Err(e) => invalid(&format!("trusted[{}]", i), e),
+            }
+        }
+        let header = config.header.as_deref().unwrap_or("x-forwarded-for");
+        let header = match HeaderName::from_bytes(header.as_bytes()) {
+            Ok(name) => name.to_string(),
+            Err(_) => {
+                invalid("header", format!("invalid header name `{}`", header));
+                String::new()
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            trusted,
+            header,
+            recursive: config.recursive.unwrap_or(false),
+        })
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl IpCidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // the bits within the prefix, shifting by the full width leaves none for `/0`
+        let prefix = u32::from(self.prefix);
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                (net.to_bits() ^ ip.to_bits()) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                (net.to_bits() ^ ip.to_bits()) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address `{}`", addr))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| {
+                    format!("invalid prefix length `{}`, expected 0 to {}", prefix, max)
+                })?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for IpCidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for IpCidr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl RedirectToHttpsResolved {
+    const STATUSES: [u16; 4] = [301, 302, 307, 308];
+
+    /// `None` if the redirect is turned off.
+    pub(crate) fn try_from_with_location(
+        config: &RedirectToHttpsConfig,
+        location: &str,
+    ) -> Result<Option<Self>, ConfigErrors> {
+        let (port, status) = match config {
+            RedirectToHttpsConfig::Enabled(false) => return Ok(None),
+            RedirectToHttpsConfig::Enabled(true) => (None, None),
+            RedirectToHttpsConfig::Detailed { port, status } => (*port, *status),
+        };
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message: message.to_string(),
+            })
+        };
+
+        let port = port.unwrap_or(443);
+        if port == 0 {
+            invalid("port", "must not be 0");
+        }
+        let status = status.unwrap_or(301);
+        if !Self::STATUSES.contains(&status) {
+            invalid("status", "must be 301, 302, 307 or 308");
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Some(Self { port, status }))
+    }
+}
+
+impl HstsResolved {
+    // what the browsers' preload lists require at least
+    const ONE_YEAR: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+    pub(crate) fn try_from_with_location(
+        config: &HstsConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let max_age = config.max_age.unwrap_or(Self::ONE_YEAR);
+        let include_subdomains = config.include_subdomains.unwrap_or(false);
+        let preload = config.preload.unwrap_or(false);
+        if preload && (!include_subdomains || max_age < Self::ONE_YEAR) {
+            return Err(ConfigErrors::from(ConfigError::Invalid {
+                location: format!("{}.preload", location),
+                message: "requires include_subdomains and a max_age of at least 1 year".to_string(),
+            }));
+        }
+        Ok(Self {
+            max_age,
+            include_subdomains,
+            preload,
+        })
+    }
+
+    /// The value of the `Strict-Transport-Security` header.
+    pub fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+impl TryFrom<&TlsConfig> for TlsConfigResolved {
+    type Error = ConfigErrors;
+
+    fn try_from(config: &TlsConfig) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        // check if the cert, key, ca, client ca and crl files exist
+        let mut files = vec![("cert", &config.cert), ("key", &config.key)];
+        for (kind, path) in [
+            ("ca", &config.ca),
+            ("client_ca", &config.client_ca),
+            ("crl", &config.crl),
+        ] {
+            if let Some(path) = path {
+                files.push((kind, path));
+            }
+        }
+        for (kind, path) in files {
+            if !Path::new(path).exists() {
+                errors.push(ConfigError::MissingFile {
+                    location: format!("global.tls.{}", kind),
+                    kind,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("global.tls.{}", field),
+                message: message.to_string(),
+            })
+        };
+        let client_auth = config.client_auth.unwrap_or_default();
+        if client_auth == ClientAuth::None {
+            if config.client_ca.is_some() {
+                invalid("client_ca", "requires client_auth `optional` or `required`");
+            }
+            if config.crl.is_some() {
+                invalid("crl", "requires client_auth `optional` or `required`");
+            }
+        } else if config.client_ca.is_none() {
+            invalid("client_ca", "is required to verify client certificates");
+        }
+
+        let profile = config.profile.unwrap_or_default();
+        if profile != TlsProfile::Custom {
+            let custom_only = [
+                ("min_version", config.min_version.is_some()),
+                ("max_version", config.max_version.is_some()),
+                ("ciphers", !config.ciphers.is_empty()),
+            ];
+            for (field, _) in custom_only.iter().filter(|(_, set)| *set) {
+                invalid(field, "only applies to profile `custom`");
+            }
+        }
+        let mut version = |field: &str, value: &Option<String>, default: TlsVersion| match value {
+            Some(value) => value.parse().unwrap_or_else(|reason: String| {
+                invalid(field, &reason);
+                default
+            }),
+            None => default,
+        };
+        let (min, max) = match profile {
+            TlsProfile::Modern => (TlsVersion::Tls13, TlsVersion::Tls13),
+            TlsProfile::Intermediate => (TlsVersion::Tls12, TlsVersion::Tls13),
+            TlsProfile::Custom => (
+                version("min_version", &config.min_version, TlsVersion::Tls12),
+                version("max_version", &config.max_version, TlsVersion::Tls13),
+            ),
+        };
+        if min > max {
+            invalid("min_version", "must not be greater than max_version");
+        }
+        let versions: Vec<TlsVersion> = [TlsVersion::Tls12, TlsVersion::Tls13]
+            .into_iter()
+            .filter(|version| (min..=max).contains(version))
+            .collect();
+        let ciphers: Vec<String> = if config.ciphers.is_empty() {
+            DEFAULT_CIPHER_SUITES
+                .iter()
+                .filter(|suite| versions.contains(&TlsVersion::of(suite)))
+                .filter_map(|suite| suite.suite().as_str())
+                .map(str::to_string)
+                .collect()
+        } else {
+            for name in config.ciphers.iter() {
+                match TlsVersion::of_cipher(name) {
+                    None => invalid("ciphers", &format!("unsupported cipher suite `{}`", name)),
+                    Some(version) if !versions.contains(&version) => invalid(
+                        "ciphers",
+                        &format!("`{}` is for TLS {}, which is not enabled", name, version),
+                    ),
+                    Some(_) => {}
+                }
+            }
+            config.ciphers.clone()
+        };
+        for version in versions.iter() {
+            if !ciphers
+                .iter()
+                .any(|name| TlsVersion::of_cipher(name) == Some(*version))
+            {
+                invalid("ciphers", &format!("no cipher suite for TLS {}", version));
+            }
+        }
+        let alpn = config
+            .alpn
+            .clone()
+            .unwrap_or_else(|| vec!["h2".to_string(), "http/1.1".to_string()]);
+        if alpn.is_empty() {
+            invalid("alpn", "must not be empty");
+        }
+        for (i, protocol) in alpn.iter().enumerate() {
+            if protocol != "h2" && protocol != "http/1.1" {
+                invalid(
+                    "alpn",
+                    &format!(
+                        "unsupported protocol `{}`, expected h2 or http/1.1",
+                        protocol
+                    ),
+                );
+            } else if alpn[..i].contains(protocol) {
+                invalid("alpn", &format!("duplicate protocol `{}`", protocol));
+            }
+        }
+
+        let default = ClientIdentityHeadersConfig::default();
+        let headers = config.client_identity_headers.as_ref().unwrap_or(&default);
+        let mut header = |field: &str, name: &Option<String>, default: &str| {
+            let name = name.as_deref().unwrap_or(default).to_ascii_lowercase();
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                invalid(
+                    &format!("client_identity_headers.{}", field),
+                    &format!("invalid header name `{}`", name),
+                );
+            }
+            name
+        };
+        let client_identity_headers = ClientIdentityHeaders {
+            subject: header("subject", &headers.subject, "x-client-subject"),
+            san: header("san", &headers.san, "x-client-san"),
+            fingerprint: header(
+                "fingerprint",
+                &headers.fingerprint,
+                "x-client-cert-fingerprint",
+            ),
+        };
+
+        let hsts = match &config.hsts {
+            Some(hsts) => errors.absorb(HstsResolved::try_from_with_location(
+                hsts,
+                "global.tls.hsts",
+            )),
+            None => None,
+        };
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            cert: config.cert.clone(),
+            key: config.key.clone(),
+            ca: config.ca.clone(),
+            client_auth,
+            client_ca: config.client_ca.clone(),
+            crl: config.crl.clone(),
+            client_identity_headers,
+            profile,
+            versions,
+            ciphers,
+            alpn,
+            session_tickets: config.session_tickets.unwrap_or(false),
+            session_cache_size: config.session_cache_size.unwrap_or(256),
+            hsts,
+        })
+    }
+}
+
+impl TlsVersion {
+    fn of(suite: &SupportedCipherSuite) -> Self {
+        match suite {
+            SupportedCipherSuite::Tls12(_) => Self::Tls12,
+            SupportedCipherSuite::Tls13(_) => Self::Tls13,
+        }
+    }
+
+    /// The version of a cipher suite by name, `None` if it is not supported.
+    pub fn of_cipher(name: &str) -> Option<Self> {
+        ALL_CIPHER_SUITES
+            .iter()
+            .find(|suite| suite.suite().as_str() == Some(name))
+            .map(Self::of)
+    }
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(version: &str) -> Result<Self, Self::Err> {
+        match version {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            "1.0" | "1.1" => Err(format!("TLS {} is not supported", version)),
+            _ => Err(format!(
+                "invalid version `{}`, expected 1.2 or 1.3",
+                version
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tls12 => write!(f, "1.2"),
+            Self::Tls13 => write!(f, "1.3"),
+        }
+    }
+}
+
+impl TryFrom<&UpstreamConfig> for UpstreamConfigResolved {
+    type Error = ConfigErrors;
+
+    fn try_from(config: &UpstreamConfig) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let location = format!("upstream `{}`", config.name);
+        if config.servers.is_empty() {
+            errors.push(ConfigError::EmptyServers {
+                location: format!("{}.servers", location),
+            });
+        }
+        let mut servers = Vec::with_capacity(config.servers.len());
+        for (i, server) in config.servers.iter().enumerate() {
+            let (weight, backup) = match server {
+                UpstreamServerConfig::Addr(_) => (1, false),
+                UpstreamServerConfig::Detailed { weight, backup, .. } => {
+                    (weight.unwrap_or(1), backup.unwrap_or(false))
+                }
+            };
+            if let Err(reason) = validate_addr(server.addr()) {
+                errors.push(ConfigError::InvalidAddress {
+                    location: format!("{}.servers[{}]", location, i),
+                    addr: server.addr().to_string(),
+                    reason,
+                });
+            }
+            if weight == 0 {
+                errors.push(ConfigError::Invalid {
+                    location: format!("{}.servers[{}].weight", location, i),
+                    message: "weight must be at least 1".to_string(),
+                });
+            }
+            servers.push(UpstreamServerResolved {
+                addr: server.addr().to_string(),
+                weight,
+                backup,
+            });
+        }
+        if !servers.is_empty() && servers.iter().all(|server| server.backup) {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.servers", location),
+                message: "at least one server must not be a backup".to_string(),
+            });
+        }
+        // pingora's ketama ring is built from ip addresses and leaves the others out
+        if config.algorithm == LoadBalanceAlgorithm::Ketama
+            && servers.iter().any(|server| server.unix_path().is_some())
+        {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.algorithm", location),
+                message: "ketama does not support unix socket servers".to_string(),
+            });
+        }
+
+        let hash_key = match (&config.hash_key, config.algorithm.is_hashing()) {
+            (Some(key), true) => match key.parse::<HashKey>() {
+                Ok(key) => Some(key),
+                Err(reason) => {
+                    errors.push(ConfigError::Invalid {
+                        location: format!("{}.hash_key", location),
+                        message: format!("invalid hash key `{}`: {}", key, reason),
+                    });
+                    None
+                }
+            },
+            (Some(_), false) => {
+                errors.push(ConfigError::Invalid {
+                    location: format!("{}.hash_key", location),
+                    message: "hash_key requires the ketama or fnv_hash algorithm".to_string(),
+                });
+                None
+            }
+            (None, true) => Some(HashKey::ClientIp),
+            (None, false) => None,
+        };
+
+        let default = HealthCheckConfig::default();
+        let health_check = errors.absorb(HealthCheckResolved::try_from_with_location(
+            config.health_check.as_ref().unwrap_or(&default),
+            &format!("{}.health_check", location),
+        ));
+
+        // only upstreams with an `outlier_detection` block eject servers
+        let outlier_detection = match config.outlier_detection.as_ref() {
+            Some(outlier_detection) => {
+                errors.absorb(OutlierDetectionResolved::try_from_with_location(
+                    outlier_detection,
+                    &format!("{}.outlier_detection", location),
+                ))
+            }
+            None => Some(None),
+        };
+
+        let timeouts = UpstreamTimeouts {
+            connect_timeout: config.connect_timeout,
+            total_connection_timeout: config.total_connection_timeout,
+            read_timeout: config.read_timeout,
+            write_timeout: config.write_timeout,
+            idle_timeout: config.idle_timeout,
+            request_timeout: config.request_timeout,
+        };
+        let fields = [
+            ("connect_timeout", timeouts.connect_timeout),
+            (
+                "total_connection_timeout",
+                timeouts.total_connection_timeout,
+            ),
+            ("read_timeout", timeouts.read_timeout),
+            ("write_timeout", timeouts.write_timeout),
+            ("idle_timeout", timeouts.idle_timeout),
+            ("request_timeout", timeouts.request_timeout),
+        ];
+        for (field, _) in fields
+            .iter()
+            .filter(|(_, timeout)| timeout.is_some_and(|timeout| timeout.is_zero()))
+        {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message: "must be greater than zero".to_string(),
+            });
+        }
+
+        // only upstreams with a `retry` block retry failed requests
+        let retry = match config.retry.as_ref() {
+            Some(retry) => errors.absorb(RetryResolved::try_from_with_location(
+                retry,
+                &format!("{}.retry", location),
+            )),
+            None => Some(RetryResolved {
+                attempts: 1,
+                ..RetryResolved::default()
+            }),
+        };
+
+        let upstream_tls = match &config.upstream_tls {
+            Some(tls) => errors.absorb(UpstreamTlsResolved::try_from_with_location(
+                tls,
+                &format!("{}.upstream_tls", location),
+            )),
+            None => None,
+        };
+
+        match (health_check, outlier_detection, retry) {
+            (Some(health_check), Some(outlier_detection), Some(retry)) if errors.is_empty() => {
+                Ok(Self {
+                    name: config.name.clone(),
+                    servers,
+                    algorithm: config.algorithm,
+                    hash_key,
+                    health_check,
+                    outlier_detection,
+                    retry,
+                    timeouts,
+                    upstream_tls,
+                    proxy_protocol: config.proxy_protocol,
+                })
+            }
+            _ => Err(errors),
+        }
+    }
+}
+
+impl ServerConfigResolved {
+    fn try_from_with_upstreams(
+        config: &ServerConfig,
+        index: usize,
+        upstreams: &HashMap<String, Option<UpstreamConfigResolved>>,
+        global: &GlobalConfig,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let global_tls = global.tls.as_ref();
+        let client_auth = global_tls
+            .and_then(|tls| tls.client_auth)
+            .unwrap_or_default();
+        // certificates are requested in the handshake, before the server is known
+        if config
+            .client_auth
+            .is_some_and(|auth| auth != ClientAuth::None)
+            && client_auth == ClientAuth::None
+        {
+            errors.push(ConfigError::Invalid {
+                location: format!("servers[{}].client_auth", index),
+                message: "requires global.tls.client_auth `optional` or `required`".to_string(),
+            });
+        }
+        for (kind, path) in [("cert", &config.cert), ("key", &config.key)] {
+            if let Some(path) = path
+                && !Path::new(path).exists()
+            {
+                errors.push(ConfigError::MissingFile {
+                    location: format!("servers[{}].{}", index, kind),
+                    kind,
+                    path: path.clone(),
+                });
+            }
+        }
+        if config.cert.is_some() != config.key.is_some() {
+            errors.push(ConfigError::Invalid {
+                location: format!("servers[{}]", index),
+                message: "cert and key must be set together".to_string(),
+            });
+        } else if config.cert.is_some() && global_tls.is_none() {
+            errors.push(ConfigError::Invalid {
+                location: format!("servers[{}].cert", index),
+                message: "requires global.tls, which serves servers without a certificate"
+                    .to_string(),
+            });
+        }
+        let listeners = global.listener_names();
+        for name in config.listeners.iter() {
+            if !listeners.contains(name) {
+                errors.push(ConfigError::Invalid {
+                    location: format!("servers[{}].listeners", index),
+                    message: format!("unknown listener `{}`", name),
+                });
+            }
+        }
+        let redirect_to_https = match &config.redirect_to_https {
+            Some(redirect) => errors
+                .absorb(RedirectToHttpsResolved::try_from_with_location(
+                    redirect,
+                    &format!("servers[{}].redirect_to_https", index),
+                ))
+                .flatten(),
+            None => None,
+        };
+        if config.hsts.is_some() && global_tls.is_none() {
+            errors.push(ConfigError::Invalid {
+                location: format!("servers[{}].hsts", index),
+                message: "requires global.tls, it is only sent over TLS".to_string(),
+            });
+        }
+        // a broken `global.tls.hsts` is reported with `global.tls`
+        let hsts = match (&config.hsts, global_tls.and_then(|tls| tls.hsts.as_ref())) {
+            (Some(hsts), _) => errors.absorb(HstsResolved::try_from_with_location(
+                hsts,
+                &format!("servers[{}].hsts", index),
+            )),
+            (None, Some(hsts)) => {
+                HstsResolved::try_from_with_location(hsts, "global.tls.hsts").ok()
+            }
+            (None, None) => None,
+        };
+        let find_upstream = |name: &str| match upstreams.get(name) {
+            Some(Some(upstream)) => Ok(upstream.clone()),
+            Some(None) => Err(ConfigErrors::default()),
+            None => Err(ConfigErrors::from(ConfigError::UpstreamNotFound {
+                index,
+                server: config.server_name.first().cloned().unwrap_or_default(),
+                upstream: name.to_string(),
+            })),
+        };
+
+        let tls = config.tls.unwrap_or(false);
+        let upstream = errors.absorb(find_upstream(&config.upstream));
+        let matches = match &config.matches {
+            Some(matches) => errors.absorb(MatchConfigResolved::try_from_with_location(
+                matches,
+                &format!("servers[{}].match", index),
+            )),
+            None => None,
+        };
+
+        let headers = |headers: &Option<HeaderRewriteConfig>, field: &str| {
+            HeaderRewriteResolved::try_from_with_location(
+                headers.as_ref(),
+                &format!("servers[{}].{}", index, field),
+            )
+        };
+        let request_headers = errors.absorb(headers(&config.request_headers, "request_headers"));
+        let response_headers = errors.absorb(headers(&config.response_headers, "response_headers"));
+
+        let mut locations: Vec<LocationConfigResolved> = Vec::new();
+        for (i, location) in config.locations.iter().enumerate() {
+            let path = match location.path.parse::<LocationPath>() {
+                Ok(path) => path,
+                Err(reason) => {
+                    errors.push(ConfigError::Invalid {
+                        location: format!("servers[{}].locations[{}].path", index, i),
+                        message: format!("invalid path `{}`: {}", location.path, reason),
+                    });
+                    continue;
+                }
+            };
+            let location_matches = match &location.matches {
+                Some(matches) => errors.absorb(MatchConfigResolved::try_from_with_location(
+                    matches,
+                    &format!("servers[{}].locations[{}].match", index, i),
+                )),
+                None => None,
+            };
+            // the same path may be repeated with different match blocks
+            if locations
+                .iter()
+                .any(|l| l.path == path && l.matches == location_matches)
+            {
+                errors.push(ConfigError::Invalid {
+                    location: format!("servers[{}].locations[{}].path", index, i),
+                    message: format!("duplicate path `{}`", location.path),
+                });
+            }
+            let location_headers = |headers: &Option<HeaderRewriteConfig>, field: &str| {
+                HeaderRewriteResolved::try_from_with_location(
+                    headers.as_ref(),
+                    &format!("servers[{}].locations[{}].{}", index, i, field),
+                )
+            };
+            let request_headers = errors.absorb(location_headers(
+                &location.request_headers,
+                "request_headers",
+            ));
+            let response_headers = errors.absorb(location_headers(
+                &location.response_headers,
+                "response_headers",
+            ));
+            if let Some(upstream) = errors.absorb(find_upstream(&location.upstream)) {
+                locations.push(LocationConfigResolved {
+                    path,
+                    tls: location.tls.unwrap_or(tls) || upstream.upstream_tls.is_some(),
+                    upstream,
+                    request_headers: request_headers.unwrap_or_default(),
+                    response_headers: response_headers.unwrap_or_default(),
+                    matches: location_matches,
+                });
+            }
+        }
+
+        match upstream {
+            Some(upstream) if errors.is_empty() => Ok(Self {
+                tls: tls || upstream.upstream_tls.is_some(),
+                client_auth: config.client_auth.unwrap_or(client_auth),
+                cert: config.cert.clone(),
+                key: config.key.clone(),
+                listeners: config.listeners.clone(),
+                redirect_to_https,
+                hsts,
+                forwarded_headers: config
+                    .forwarded_headers
+                    .as_ref()
+                    .map(ForwardedHeadersResolved::from)
+                    .unwrap_or_default(),
+                request_headers: request_headers.unwrap_or_default(),
+                response_headers: response_headers.unwrap_or_default(),
+                upstream,
+                locations,
+                matches,
+            }),
+            _ => Err(errors),
+        }
+    }
+
+    /// Whether the server is reachable on the listener named `listener`.
+    pub fn listens_on(&self, listener: &str) -> bool {
+        self.listeners.is_empty() || self.listeners.iter().any(|name| name == listener)
+    }
+
+    pub fn choose(&self) -> Option<&str> {
+        self.upstream
+            .servers
+            .choose(&mut rand::thread_rng())
+            .map(|s| s.addr.as_str())
+    }
+}
+
+impl MatchConfigResolved {
+    pub(crate) fn try_from_with_location(
+        config: &MatchConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |message: String| {
+            errors.push(ConfigError::Invalid {
+                location: location.to_string(),
+                message,
+            })
+        };
+
+        let mut methods = Vec::new();
+        for method in config.method.iter() {
+            let method = method.to_ascii_uppercase();
+            match Method::from_bytes(method.as_bytes()) {
+                Ok(_) => methods.push(method),
+                Err(_) => invalid(format!("invalid method `{}`", method)),
+            }
+        }
+
+        let mut values = |values: &BTreeMap<String, String>, kind: &str| {
+            let mut resolved = BTreeMap::new();
+            for (name, value) in values.iter() {
+                match value.parse::<ValueMatch>() {
+                    Ok(value) => {
+                        resolved.insert(name.clone(), value);
+                    }
+                    Err(reason) => invalid(format!("invalid {} `{}`: {}", kind, name, reason)),
+                }
+            }
+            resolved
+        };
+        let headers = values(&config.headers, "header")
+            .into_iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .collect();
+        let query = values(&config.query, "query parameter");
+        let cookies = values(&config.cookies, "cookie");
+
+        for name in config.headers.keys() {
+            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                invalid(format!("invalid header name `{}`", name));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            methods,
+            headers,
+            query,
+            cookies,
+        })
+    }
+}
+
+impl HeaderRewriteResolved {
+    /// Empty for no config.
+    pub(crate) fn try_from_with_location(
+        config: Option<&HeaderRewriteConfig>,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let Some(config) = config else {
+            return Ok(Self::default());
+        };
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: String| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message,
+            })
+        };
+
+        let mut names = |headers: &mut dyn Iterator<Item = &String>, field: &str| {
+            let mut lowercase = Vec::new();
+            for name in headers {
+                match HeaderName::from_bytes(name.as_bytes()) {
+                    Ok(name) if lowercase.contains(&name.to_string()) => {
+                        invalid(field, format!("duplicate header `{}`", name))
+                    }
+                    Ok(name) => lowercase.push(name.to_string()),
+                    Err(_) => invalid(field, format!("invalid header name `{}`", name)),
+                }
+            }
+            lowercase
+        };
+        let set_names = names(&mut config.set.keys(), "set");
+        let add_names = names(&mut config.add.keys(), "add");
+        let remove = names(&mut config.remove.iter(), "remove");
+
+        for (field, values) in [("set", &config.set), ("add", &config.add)] {
+            for (name, value) in values.iter() {
+                if let Err(reason) = value.parse::<HeaderTemplate>() {
+                    invalid(field, format!("invalid value of `{}`: {}", name, reason));
+                }
+            }
+        }
+        for pattern in config.remove_matching.iter() {
+            if let Err(e) = regex::Regex::new(pattern) {
+                invalid("remove_matching", e.to_string());
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            set: set_names
+                .into_iter()
+                .zip(config.set.values().cloned())
+                .collect(),
+            add: add_names
+                .into_iter()
+                .zip(config.add.values().cloned())
+                .collect(),
+            remove,
+            remove_matching: config.remove_matching.clone(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+            && self.add.is_empty()
+            && self.remove.is_empty()
+            && self.remove_matching.is_empty()
+    }
+}
+
+impl From<&ForwardedHeadersConfig> for ForwardedHeadersResolved {
+    fn from(config: &ForwardedHeadersConfig) -> Self {
+        let default = Self::default();
+        Self {
+            x_forwarded_for: config.x_forwarded_for.unwrap_or(default.x_forwarded_for),
+            forwarded: config.forwarded.unwrap_or(default.forwarded),
+            x_forwarded_proto: config
+                .x_forwarded_proto
+                .unwrap_or(default.x_forwarded_proto),
+            x_forwarded_host: config.x_forwarded_host.unwrap_or(default.x_forwarded_host),
+            x_forwarded_port: config.x_forwarded_port.unwrap_or(default.x_forwarded_port),
+            x_real_ip: config.x_real_ip.unwrap_or(default.x_real_ip),
+        }
+    }
+}
+
+impl Default for ForwardedHeadersResolved {
+    fn default() -> Self {
+        Self {
+            x_forwarded_for: ForwardedMode::Append,
+            forwarded: ForwardedMode::Off,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            x_forwarded_port: true,
+            x_real_ip: true,
+        }
+    }
+}
+
+impl Default for HealthCheckResolved {
+    fn default() -> Self {
+        Self {
+            kind: HealthCheckKind::Tcp,
+            interval: Self::DEFAULT_INTERVAL,
+            timeout: Self::DEFAULT_TIMEOUT,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        }
+    }
+}
+
+impl HealthCheckResolved {
+    const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+    const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub(crate) fn try_from_with_location(
+        config: &HealthCheckConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message: message.to_string(),
+            })
+        };
+
+        let kind = match config.kind {
+            HealthCheckType::Tcp => {
+                let http_only = [
+                    ("path", config.path.is_some()),
+                    ("host", config.host.is_some()),
+                    ("expected_status", !config.expected_status.is_empty()),
+                    ("body", config.body.is_some()),
+                ];
+                for (field, _) in http_only.iter().filter(|(_, set)| *set) {
+                    invalid(field, "only applies to http health checks");
+                }
+                HealthCheckKind::Tcp
+            }
+            HealthCheckType::Http => {
+                let path = config.path.clone().unwrap_or_else(|| "/".to_string());
+                if !path.starts_with('/') || path.parse::<Uri>().is_err() {
+                    invalid("path", "must be a path starting with `/`");
+                }
+                if config
+                    .expected_status
+                    .iter()
+                    .any(|status| !(100..=599).contains(status))
+                {
+                    invalid("expected_status", "status codes must be within 100-599");
+                }
+                if config.body.as_ref().is_some_and(|body| body.is_empty()) {
+                    invalid("body", "must not be empty");
+                }
+                let expected_status = if config.expected_status.is_empty() {
+                    vec![200]
+                } else {
+                    config.expected_status.clone()
+                };
+                HealthCheckKind::Http(HttpHealthCheckResolved {
+                    path,
+                    host: config
+                        .host
+                        .clone()
+                        .unwrap_or_else(|| "localhost".to_string()),
+                    expected_status,
+                    body: config.body.clone(),
+                })
+            }
+        };
+
+        let interval = config.interval.unwrap_or(Self::DEFAULT_INTERVAL);
+        let timeout = config.timeout.unwrap_or(Self::DEFAULT_TIMEOUT);
+        if interval.is_zero() {
+            invalid("interval", "must be greater than zero");
+        }
+        if timeout.is_zero() {
+            invalid("timeout", "must be greater than zero");
+        }
+        let healthy_threshold = config.healthy_threshold.unwrap_or(1);
+        let unhealthy_threshold = config.unhealthy_threshold.unwrap_or(1);
+        if healthy_threshold == 0 {
+            invalid("healthy_threshold", "must be at least 1");
+        }
+        if unhealthy_threshold == 0 {
+            invalid("unhealthy_threshold", "must be at least 1");
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            kind,
+            interval,
+            timeout,
+            healthy_threshold,
+            unhealthy_threshold,
+        })
+    }
+}
+
+impl OutlierDetectionResolved {
+    /// `Ok(None)` if the block sets `enabled: false`.
+    pub(crate) fn try_from_with_location(
+        config: &OutlierDetectionConfig,
+        location: &str,
+    ) -> Result<Option<Self>, ConfigErrors> {
+        if !config.enabled.unwrap_or(true) {
+            return Ok(None);
+        }
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message: message.to_string(),
+            })
+        };
+
+        let resolved = Self {
+            consecutive_5xx: config.consecutive_5xx.unwrap_or(5),
+            consecutive_connect_failures: config.consecutive_connect_failures.unwrap_or(5),
+            error_rate: config.error_rate,
+            window: config.window.unwrap_or(Duration::from_secs(10)),
+            min_requests: config.min_requests.unwrap_or(20),
+            base_ejection_time: config.base_ejection_time.unwrap_or(Duration::from_secs(30)),
+            max_ejection_time: config.max_ejection_time.unwrap_or(Duration::from_secs(300)),
+            max_ejection_percent: config.max_ejection_percent.unwrap_or(50),
+        };
+        if resolved
+            .error_rate
+            .is_some_and(|rate| !(rate > 0.0 && rate <= 1.0))
+        {
+            invalid("error_rate", "must be within (0, 1]");
+        }
+        if resolved.window.is_zero() {
+            invalid("window", "must be greater than zero");
+        }
+        if resolved.base_ejection_time.is_zero() {
+            invalid("base_ejection_time", "must be greater than zero");
+        }
+        if resolved.max_ejection_time < resolved.base_ejection_time {
+            invalid(
+                "max_ejection_time",
+                "must not be shorter than base_ejection_time",
+            );
+        }
+        if resolved.max_ejection_percent > 100 {
+            invalid("max_ejection_percent", "must be within 0-100");
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Some(resolved))
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct TlsConfigResolved {
-    pub cert: String,
-    pub key: String,
-    pub ca: Option<String>,
-}
+impl UpstreamTlsResolved {
+    pub(crate) fn try_from_with_location(
+        config: &UpstreamTlsConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let files = [
+            ("ca", &config.ca),
+            ("client_cert", &config.client_cert),
+            ("client_key", &config.client_key),
+        ];
+        for (kind, path) in files {
+            if let Some(path) = path
+                && !Path::new(path).exists()
+            {
+                errors.push(ConfigError::MissingFile {
+                    location: format!("{}.{}", location, kind),
+                    kind,
+                    path: path.clone(),
+                });
+            }
+        }
+        if config.client_cert.is_some() != config.client_key.is_some() {
+            errors.push(ConfigError::Invalid {
+                location: location.to_string(),
+                message: "client_cert and client_key must be set together".to_string(),
+            });
+        }
+        if config.sni.as_ref().is_some_and(|sni| sni.is_empty()) {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.sni", location),
+                message: "must not be empty".to_string(),
+            });
+        }
 
-#[derive(Debug, Clone)]
-pub struct ServerConfigResolved {
-    pub upstream: UpstreamConfigResolved,
-    pub tls: bool,
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            sni: config.sni.clone(),
+            verify_cert: config.verify_cert.unwrap_or(true),
+            verify_hostname: config.verify_hostname.unwrap_or(true),
+            ca: config.ca.clone(),
+            client_cert: config.client_cert.clone(),
+            client_key: config.client_key.clone(),
+            alpn: config.alpn.unwrap_or_default(),
+        })
+    }
 }
 
-#[derive(Debug, Clone)]
-pub struct UpstreamConfigResolved {
-    pub name: String,
-    pub servers: Vec<String>,
+impl Default for RetryResolved {
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            retry_on: vec![RetryOn::ConnectFailure],
+            statuses: Vec::new(),
+            idempotent_only: true,
+            per_try_timeout: None,
+            budget: 0.2,
+        }
+    }
 }
 
-impl TryFrom<SimpleProxyConfig> for SimpleProxyConfigResolved {
-    type Error = anyhow::Error;
+impl RetryResolved {
+    // pingora gives up on a request after this many tries
+    const MAX_ATTEMPTS: usize = 16;
 
-    fn try_from(config: SimpleProxyConfig) -> anyhow::Result<Self> {
-        let global = GlobalConfigResolved::try_from(&config.global)?;
+    pub(crate) fn try_from_with_location(
+        config: &RetryConfig,
+        location: &str,
+    ) -> Result<Self, ConfigErrors> {
+        let mut errors = ConfigErrors::default();
+        let mut invalid = |field: &str, message: &str| {
+            errors.push(ConfigError::Invalid {
+                location: format!("{}.{}", location, field),
+                message: message.to_string(),
+            })
+        };
 
-        let upstreams: HashMap<String, UpstreamConfigResolved> = config
-            .upstreams
+        let default = Self::default();
+        let attempts = config.attempts.unwrap_or(default.attempts);
+        if !(1..=Self::MAX_ATTEMPTS).contains(&attempts) {
+            invalid("attempts", "must be within 1-16");
+        }
+        if config
+            .statuses
             .iter()
-            .map(|upstream| {
-                (
-                    upstream.name.clone(),
-                    UpstreamConfigResolved::from(upstream),
-                )
-            })
-            .collect();
-        let mut servers = HashMap::new();
-        for server in config.servers {
-            let server_resolved =
-                ServerConfigResolved::try_from_with_upstreams(&server, &upstreams)?;
-            for name in server.server_name {
-                servers.insert(name, server_resolved.clone());
-            }
+            .any(|status| !(100..=599).contains(status))
+        {
+            invalid("statuses", "status codes must be within 100-599");
         }
-        Ok(Self { global, servers })
+        if config
+            .per_try_timeout
+            .is_some_and(|timeout| timeout.is_zero())
+        {
+            invalid("per_try_timeout", "must be greater than zero");
+        }
+        let budget = config.budget.unwrap_or(default.budget);
+        if !(0.0..=1.0).contains(&budget) {
+            invalid("budget", "must be within 0-1");
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Self {
+            attempts,
+            retry_on: if config.retry_on.is_empty() {
+                default.retry_on
+            } else {
+                config.retry_on.clone()
+            },
+            statuses: config.statuses.clone(),
+            idempotent_only: config.idempotent_only.unwrap_or(default.idempotent_only),
+            per_try_timeout: config.per_try_timeout,
+            budget,
+        })
     }
 }
 
-impl TryFrom<&GlobalConfig> for GlobalConfigResolved {
-    type Error = anyhow::Error;
+impl FromStr for HashKey {
+    type Err = String;
 
-    fn try_from(config: &GlobalConfig) -> anyhow::Result<Self> {
-        let tls = match &config.tls {
-            Some(tls) => Some(TlsConfigResolved::try_from(tls)?),
-            None => None,
-        };
-        Ok(Self {
-            port: config.port,
-            tls,
-        })
+    fn from_str(key: &str) -> Result<Self, Self::Err> {
+        match key.split_once(':') {
+            Some(("header", name)) => HeaderName::from_bytes(name.as_bytes())
+                .map(|name| Self::Header(name.to_string()))
+                .map_err(|_| format!("invalid header name `{}`", name)),
+            Some(("cookie", name)) if !name.is_empty() => Ok(Self::Cookie(name.to_string())),
+            None if key == "client_ip" => Ok(Self::ClientIp),
+            None if key == "uri" => Ok(Self::Uri),
+            None if key == "path" => Ok(Self::Path),
+            _ => Err("expected client_ip, uri, path, header:<name> or cookie:<name>".to_string()),
+        }
     }
 }
 
-impl TryFrom<&TlsConfig> for TlsConfigResolved {
-    type Error = anyhow::Error;
+impl FromStr for HeaderTemplate {
+    type Err = String;
 
-    fn try_from(config: &TlsConfig) -> anyhow::Result<Self> {
-        // check if the cert and key exist
-        let cert_path = Path::new(&config.cert);
-        if !cert_path.exists() {
-            return Err(anyhow::anyhow!("cert file does not exist"));
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = value;
+        while let Some(i) = rest.find('$') {
+            literal.push_str(&rest[..i]);
+            rest = &rest[i + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => braced
+                    .split_once('}')
+                    .ok_or_else(|| "unclosed `${`".to_string())?,
+                None => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_lowercase() && c != '_')
+                        .unwrap_or(rest.len());
+                    rest.split_at(end)
+                }
+            };
+            let variable = match name {
+                "client_ip" => HeaderVariable::ClientIp,
+                "host" => HeaderVariable::Host,
+                "scheme" => HeaderVariable::Scheme,
+                "request_id" => HeaderVariable::RequestId,
+                "upstream_addr" => HeaderVariable::UpstreamAddr,
+                "" => return Err("`$` without a variable name, `$$` is a literal `$`".to_string()),
+                _ => {
+                    return Err(format!(
+                        "unknown variable `${}`, expected $client_ip, $host, $scheme, \
+                         $request_id or $upstream_addr",
+                        name
+                    ));
+                }
+            };
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(TemplatePart::Variable(variable));
+            rest = after;
         }
-        let key_path = Path::new(&config.key);
-        if !key_path.exists() {
-            return Err(anyhow::anyhow!("key file does not exist"));
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
         }
 
-        // check if the ca file exists
-        if let Some(ca) = &config.ca {
-            let ca_path = Path::new(&ca);
-            if !ca_path.exists() {
-                return Err(anyhow::anyhow!("ca file does not exist"));
+        for part in parts.iter() {
+            if let TemplatePart::Literal(literal) = part
+                && HeaderValue::from_str(literal).is_err()
+            {
+                return Err("only visible ASCII characters and spaces are allowed".to_string());
             }
         }
-        Ok(Self {
-            cert: config.cert.clone(),
-            key: config.key.clone(),
-            ca: config.ca.clone(),
-        })
+        Ok(Self(parts))
     }
 }
 
-impl From<&UpstreamConfig> for UpstreamConfigResolved {
-    fn from(config: &UpstreamConfig) -> Self {
-        Self {
-            name: config.name.clone(),
-            servers: config.servers.clone(),
+impl FromStr for ValueMatch {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix('~') {
+            Some(pattern) => {
+                regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+                Ok(Self::Regex(pattern.to_string()))
+            }
+            None => Ok(Self::Exact(value.to_string())),
         }
     }
 }
 
-impl ServerConfigResolved {
-    fn try_from_with_upstreams(
-        config: &ServerConfig,
-        upstreams: &HashMap<String, UpstreamConfigResolved>,
-    ) -> anyhow::Result<Self> {
-        let tls = config.tls.unwrap_or(false);
-        let upstream = upstreams
-            .get(&config.upstream)
-            .ok_or(anyhow::anyhow!("upstream not found"))?
-            .clone();
-        Ok(Self { upstream, tls })
+impl FromStr for LocationPath {
+    type Err = String;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = path.strip_prefix('~') {
+            let pattern = pattern.trim_start();
+            regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+            return Ok(Self::Regex(pattern.to_string()));
+        }
+
+        let (path, exact) = match path.strip_prefix('=') {
+            Some(path) => (path.trim_start(), true),
+            None => (path, false),
+        };
+        if !path.starts_with('/') {
+            return Err("path must start with `/`".to_string());
+        }
+        if exact {
+            Ok(Self::Exact(path.to_string()))
+        } else {
+            Ok(Self::Prefix(path.to_string()))
+        }
     }
+}
 
-    pub fn choose(&self) -> Option<&str> {
-        self.upstream
-            .servers
-            .choose(&mut rand::thread_rng())
-            .map(|s| s.as_str())
+impl FromStr for ServerName {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = name.strip_prefix('~') {
+            regex::Regex::new(pattern).map_err(|e| e.to_string())?;
+            return Ok(Self::Regex(pattern.to_string()));
+        }
+        if name.is_empty() {
+            return Err("name must not be empty".to_string());
+        }
+
+        let name = name.to_ascii_lowercase();
+        let server_name = if let Some(suffix) = name.strip_prefix('*') {
+            Self::Suffix(suffix.to_string())
+        } else if let Some(prefix) = name.strip_suffix('*') {
+            Self::Prefix(prefix.to_string())
+        } else {
+            Self::Exact(name.clone())
+        };
+        let valid = match &server_name {
+            Self::Suffix(suffix) => suffix.len() > 1 && suffix.starts_with('.'),
+            Self::Prefix(prefix) => prefix.len() > 1 && prefix.ends_with('.'),
+            Self::Exact(name) => !name.contains('*'),
+            Self::Regex(_) => true,
+        };
+        if !valid || name.matches('*').count() > 1 {
+            return Err("wildcard must be a leading `*.` or a trailing `.*`".to_string());
+        }
+        Ok(server_name)
+    }
+}
+
+fn serialize_sorted<S, V>(map: &HashMap<String, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+fn serialize_mode<S: Serializer>(mode: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => serializer.collect_str(&format_args!("{:03o}", mode)),
+        None => serializer.serialize_none(),
+    }
+}
+
+// only the syntax is checked here, host names are resolved when the load balancer is built
+fn validate_addr(addr: &str) -> Result<(), String> {
+    if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+        return validate_unix_path(path);
+    }
+    let Some((host, port)) = addr.rsplit_once(':') else {
+        return Err("expected host:port".to_string());
+    };
+    if host.is_empty() {
+        return Err("missing host".to_string());
     }
+    if let Err(e) = port.parse::<u16>() {
+        return Err(format!("invalid port `{}`: {}", port, e));
+    }
+    match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => ip
+            .parse::<Ipv6Addr>()
+            .map(|_| ())
+            .map_err(|e| format!("invalid ipv6 address `{}`: {}", ip, e)),
+        None if host.contains(':') => Err("ipv6 addresses must be enclosed in []".to_string()),
+        None => Ok(()),
+    }
+}
+
+fn validate_unix_path(path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("missing socket path".to_string());
+    }
+    if !path.starts_with('/') {
+        return Err(format!("socket path `{}` must be absolute", path));
+    }
+    if path.len() > MAX_UNIX_PATH {
+        return Err(format!(
+            "socket path `{}` is longer than {} bytes",
+            path, MAX_UNIX_PATH
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::conf::raw::{SimpleProxyConfig, TlsConfig};
+    use crate::conf::raw::{SimpleProxyConfig, TlsConfig, TlsProfile};
     use std::path::PathBuf;
 
     fn get_test_config_path() -> PathBuf {
@@ -152,11 +2241,15 @@ mod tests {
 
     #[test]
     fn test_config_resolution() -> anyhow::Result<()> {
-        let config = SimpleProxyConfig::new(get_test_config_path());
+        let config = SimpleProxyConfig::new(get_test_config_path())?;
         let resolved = SimpleProxyConfigResolved::try_from(config)?;
 
         // Test global config
-        assert_eq!(resolved.global.port, 8080);
+        assert_eq!(resolved.global.listeners.len(), 1);
+        let listener = &resolved.global.listeners[0];
+        assert_eq!(listener.name, "0.0.0.0:8080");
+        assert_eq!(listener.addr, ListenerAddr::Inet("0.0.0.0:8080".parse()?));
+        assert!(!listener.tls && !listener.http2);
         assert!(resolved.global.tls.is_none());
 
         // Test servers
@@ -166,7 +2259,12 @@ mod tests {
         let web_server = resolved.servers.get("acme.com").unwrap();
         assert_eq!(web_server.upstream.name, "web_servers");
         assert_eq!(
-            web_server.upstream.servers,
+            web_server
+                .upstream
+                .servers
+                .iter()
+                .map(|s| s.addr.as_str())
+                .collect::<Vec<_>>(),
             vec!["127.0.0.1:3001", "127.0.0.1:3002"]
         );
         assert!(!web_server.tls);
@@ -175,7 +2273,12 @@ mod tests {
         let api_server = resolved.servers.get("api.acme.com").unwrap();
         assert_eq!(api_server.upstream.name, "api_servers");
         assert_eq!(
-            api_server.upstream.servers,
+            api_server
+                .upstream
+                .servers
+                .iter()
+                .map(|s| s.addr.as_str())
+                .collect::<Vec<_>>(),
             vec!["127.0.0.1:3003", "127.0.0.1:3004"]
         );
         assert!(api_server.tls);
@@ -190,6 +2293,7 @@ mod tests {
             cert: "non_existent.cert".to_string(),
             key: "non_existent.key".to_string(),
             ca: None,
+            ..Default::default()
         };
 
         let result = TlsConfigResolved::try_from(&tls_config);
@@ -206,6 +2310,7 @@ mod tests {
             cert: "./fixtures/certs/sample.crt".to_string(),
             key: "./fixtures/certs/non_existent.key".to_string(),
             ca: None,
+            ..Default::default()
         };
 
         let result = TlsConfigResolved::try_from(&tls_config);
@@ -222,6 +2327,7 @@ mod tests {
             cert: "./fixtures/certs/sample.crt".to_string(),
             key: "./fixtures/certs/sample.key".to_string(),
             ca: Some("./fixtures/certs/non_existent.ca".to_string()),
+            ..Default::default()
         };
 
         let result = TlsConfigResolved::try_from(&tls_config);
@@ -236,7 +2342,7 @@ mod tests {
 
     #[test]
     fn test_upstream_resolution() -> anyhow::Result<()> {
-        let config = SimpleProxyConfig::new(get_test_config_path());
+        let config = SimpleProxyConfig::new(get_test_config_path())?;
         let resolved = SimpleProxyConfigResolved::try_from(config)?;
 
         // Test web upstream resolution
@@ -247,13 +2353,15 @@ mod tests {
             web_server
                 .upstream
                 .servers
-                .contains(&"127.0.0.1:3001".to_string())
+                .iter()
+                .any(|s| s.addr == "127.0.0.1:3001")
         );
         assert!(
             web_server
                 .upstream
                 .servers
-                .contains(&"127.0.0.1:3002".to_string())
+                .iter()
+                .any(|s| s.addr == "127.0.0.1:3002")
         );
 
         // Test api upstream resolution
@@ -264,15 +2372,878 @@ mod tests {
             api_server
                 .upstream
                 .servers
-                .contains(&"127.0.0.1:3003".to_string())
+                .iter()
+                .any(|s| s.addr == "127.0.0.1:3003")
         );
         assert!(
             api_server
                 .upstream
                 .servers
-                .contains(&"127.0.0.1:3004".to_string())
+                .iter()
+                .any(|s| s.addr == "127.0.0.1:3004")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_diagnostics() {
+        let yaml = r#"
+global:
+  port: 3000
+servers:
+  - server_name: [acme.com, www.acme.com]
+    upstream: web_servers
+  - server_name: [acme.com]
+    upstream: missing
+upstreams:
+  - name: web_servers
+    servers: ["127.0.0.1:3001", "127.0.0.1", "[::1:3002"]
+  - name: web_servers
+    servers: ["127.0.0.1:3003"]
+  - name: empty
+    servers: []
+"#;
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+
+        let errors: Vec<_> = errors.iter().collect();
+        assert_eq!(errors.len(), 6);
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::DuplicateUpstream { name, index: 1, first: 0 } if name == "web_servers"
+        )));
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::EmptyServers { location } if location == "upstream `empty`.servers"
+        )));
+        assert_eq!(
+            errors
+                .iter()
+                .filter(|e| matches!(e, ConfigError::InvalidAddress { .. }))
+                .count(),
+            2
+        );
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::DuplicateServerName { name, index: 1, first: 0 } if name == "acme.com"
+        )));
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::UpstreamNotFound { index: 1, upstream, .. } if upstream == "missing"
+        )));
+
+        // the same errors at the line and column of the node they are about
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let errors = SimpleProxyConfigResolved::try_from(config)
+            .unwrap_err()
+            .locate(Path::new("proxy.yml"), yaml);
+        let messages: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(messages.len(), 6);
+        for expected in [
+            "proxy.yml:7:18: servers[1]: server_name `acme.com` is already used by servers[0]",
+            "proxy.yml:8:15: servers[1] (acme.com): upstream `missing` not found",
+            "proxy.yml:11:33: upstream `web_servers`.servers[1]: invalid address `127.0.0.1`",
+            "proxy.yml:11:46: upstream `web_servers`.servers[2]: invalid address `[::1:3002`",
+            "proxy.yml:12:11: upstreams[1]: upstream `web_servers` is already defined",
+            "proxy.yml:15:14: upstream `empty`.servers: list must not be empty",
+        ] {
+            assert!(
+                messages.iter().any(|m| m.starts_with(expected)),
+                "{} not in {:?}",
+                expected,
+                messages
+            );
+        }
+    }
+
+    #[test]
+    fn test_upstream_hash_key() {
+        let upstream = |yaml: &str| {
+            let config: UpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+            UpstreamConfigResolved::try_from(&config)
+        };
+
+        let resolved = upstream("{name: a, servers: [\"127.0.0.1:3001\"], algorithm: ketama}");
+        assert_eq!(resolved.unwrap().hash_key, Some(HashKey::ClientIp));
+        let resolved = upstream(
+            "{name: a, servers: [\"127.0.0.1:3001\"], algorithm: fnv_hash, hash_key: \"cookie:session\"}",
+        );
+        assert_eq!(
+            resolved.unwrap().hash_key,
+            Some(HashKey::Cookie("session".to_string()))
+        );
+        assert!(upstream("{name: a, servers: [\"127.0.0.1:3001\"], hash_key: uri}").is_err());
+        assert!(
+            upstream("{name: a, servers: [\"127.0.0.1:3001\"], algorithm: ketama, hash_key: body}")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_upstream_servers() {
+        let yaml = r#"
+name: web_servers
+servers:
+  - "127.0.0.1:3001"
+  - addr: "127.0.0.1:3002"
+    weight: 3
+  - addr: "127.0.0.1:3003"
+    backup: true
+"#;
+        let config: UpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+        let resolved = UpstreamConfigResolved::try_from(&config).unwrap();
+        assert_eq!(
+            resolved.servers,
+            vec![
+                UpstreamServerResolved {
+                    addr: "127.0.0.1:3001".to_string(),
+                    weight: 1,
+                    backup: false,
+                },
+                UpstreamServerResolved {
+                    addr: "127.0.0.1:3002".to_string(),
+                    weight: 3,
+                    backup: false,
+                },
+                UpstreamServerResolved {
+                    addr: "127.0.0.1:3003".to_string(),
+                    weight: 1,
+                    backup: true,
+                },
+            ]
+        );
+
+        let yaml = r#"
+name: web_servers
+servers:
+  - addr: "127.0.0.1:3001"
+    weight: 0
+    backup: true
+"#;
+        let config: UpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+        let errors = UpstreamConfigResolved::try_from(&config).unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_health_check() {
+        let health_check = |yaml: &str| {
+            let config: HealthCheckConfig = serde_yaml::from_str(yaml).unwrap();
+            HealthCheckResolved::try_from_with_location(&config, "health_check")
+        };
+
+        assert_eq!(health_check("{}").unwrap(), HealthCheckResolved::default());
+        let resolved = health_check(
+            "{type: http, path: /health, expected_status: [200, 204], body: ok, interval: 5s, timeout: 500ms, unhealthy_threshold: 3}",
+        )
+        .unwrap();
+        assert_eq!(
+            resolved.kind,
+            HealthCheckKind::Http(HttpHealthCheckResolved {
+                path: "/health".to_string(),
+                host: "localhost".to_string(),
+                expected_status: vec![200, 204],
+                body: Some("ok".to_string()),
+            })
+        );
+        assert_eq!(resolved.interval, Duration::from_secs(5));
+        assert_eq!(resolved.timeout, Duration::from_millis(500));
+        assert_eq!(resolved.healthy_threshold, 1);
+        assert_eq!(resolved.unhealthy_threshold, 3);
+
+        // http options on a tcp check, a bad status and a zero threshold
+        let errors = health_check("{path: /health}").unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+        let errors =
+            health_check("{type: http, expected_status: [42], healthy_threshold: 0}").unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_outlier_detection() {
+        let outlier_detection = |block: &str| {
+            let yaml = format!(
+                "name: web_servers\nservers: [\"127.0.0.1:3001\"]\n{}",
+                block
+            );
+            let config: UpstreamConfig = serde_yaml::from_str(&yaml).unwrap();
+            UpstreamConfigResolved::try_from(&config).map(|upstream| upstream.outlier_detection)
+        };
+
+        // disabled unless the upstream has the block
+        assert_eq!(outlier_detection("").unwrap(), None);
+        assert_eq!(
+            outlier_detection("outlier_detection: {enabled: false}").unwrap(),
+            None
+        );
+        let resolved = outlier_detection("outlier_detection: {}").unwrap().unwrap();
+        assert_eq!(resolved.consecutive_5xx, 5);
+        assert_eq!(resolved.base_ejection_time, Duration::from_secs(30));
+        let resolved = outlier_detection("outlier_detection: {enabled: true, error_rate: 0.5}")
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolved.error_rate, Some(0.5));
+
+        let errors =
+            outlier_detection("outlier_detection: {error_rate: 2, window: 0s}").unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_upstream_timeouts() {
+        let yaml = r#"
+name: web_servers
+servers: ["127.0.0.1:3001"]
+connect_timeout: 1s
+read_timeout: 30s
+request_timeout: 1m
+"#;
+        let config: UpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+        let resolved = UpstreamConfigResolved::try_from(&config).unwrap();
+        assert_eq!(
+            resolved.timeouts,
+            UpstreamTimeouts {
+                connect_timeout: Some(Duration::from_secs(1)),
+                read_timeout: Some(Duration::from_secs(30)),
+                request_timeout: Some(Duration::from_secs(60)),
+                ..Default::default()
+            }
+        );
+
+        let yaml = r#"
+name: web_servers
+servers: ["127.0.0.1:3001"]
+idle_timeout: 0s
+"#;
+        let config: UpstreamConfig = serde_yaml::from_str(yaml).unwrap();
+        let errors = UpstreamConfigResolved::try_from(&config).unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_upstream_tls() {
+        let upstream_tls = |yaml: &str| {
+            let config: UpstreamTlsConfig = serde_yaml::from_str(yaml).unwrap();
+            UpstreamTlsResolved::try_from_with_location(&config, "upstream_tls")
+        };
+
+        let resolved = upstream_tls(
+            "{sni: backend.acme.com, client_cert: ./fixtures/certs/proxy.crt, client_key: ./fixtures/certs/proxy.key, alpn: h2h1}",
+        )
+        .unwrap();
+        assert_eq!(resolved.sni.as_deref(), Some("backend.acme.com"));
+        assert_eq!(resolved.alpn, Alpn::H2H1);
+
+        // a missing client certificate and a client certificate without its key
+        let errors = upstream_tls("{client_cert: ./fixtures/certs/missing.crt}").unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+
+        // verification is on unless turned off
+        assert!(resolved.verify_cert && resolved.verify_hostname);
+        let resolved =
+            upstream_tls("{ca: ./fixtures/certs/ca.crt, verify_hostname: false}").unwrap();
+        assert_eq!(resolved.ca.as_deref(), Some("./fixtures/certs/ca.crt"));
+        assert!(resolved.verify_cert && !resolved.verify_hostname);
+        let errors = upstream_tls("{ca: ./fixtures/certs/missing.crt}").unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+
+        // routes to an upstream with upstream_tls use tls
+        let yaml = r#"
+global:
+  port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+    upstream_tls: {}
+"#;
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let resolved = SimpleProxyConfigResolved::try_from(config).unwrap();
+        assert!(resolved.servers["acme.com"].tls);
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let yaml = r#"
+global:
+  port: 8443
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+    client_auth: optional
+    client_ca: ./fixtures/certs/ca.crt
+    client_identity_headers: {subject: X-Ssl-Client-Dn}
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+  - server_name: [api.acme.com]
+    upstream: backend
+    client_auth: required
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let resolved = SimpleProxyConfigResolved::try_from(config).unwrap();
+        let tls = resolved.global.tls.as_ref().unwrap();
+        assert_eq!(tls.client_auth, ClientAuth::Optional);
+        assert_eq!(tls.client_identity_headers.subject, "x-ssl-client-dn");
+        assert_eq!(tls.client_identity_headers.san, "x-client-san");
+        assert_eq!(
+            resolved.servers["acme.com"].client_auth,
+            ClientAuth::Optional
+        );
+        assert_eq!(
+            resolved.servers["api.acme.com"].client_auth,
+            ClientAuth::Required
+        );
+
+        // a crl without client_auth, and a server asking for certificates the listener never
+        // requests
+        let yaml = r#"
+global:
+  port: 8443
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+    crl: ./fixtures/certs/ca.crt
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    client_auth: required
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_tls_policy() {
+        let tls = |yaml: &str| {
+            let mut config: TlsConfig = serde_yaml::from_str(yaml).unwrap();
+            config.cert = "./fixtures/certs/proxy.crt".to_string();
+            config.key = "./fixtures/certs/proxy.key".to_string();
+            TlsConfigResolved::try_from(&config)
+        };
+
+        let resolved = tls("{cert: '', key: ''}").unwrap();
+        assert_eq!(resolved.profile, TlsProfile::Intermediate);
+        assert_eq!(
+            resolved.versions,
+            vec![TlsVersion::Tls12, TlsVersion::Tls13]
+        );
+        assert_eq!(resolved.alpn, vec!["h2", "http/1.1"]);
+        assert!(!resolved.session_tickets);
+
+        let resolved = tls("{cert: '', key: '', profile: modern}").unwrap();
+        assert_eq!(resolved.versions, vec![TlsVersion::Tls13]);
+        assert!(
+            resolved
+                .ciphers
+                .iter()
+                .all(|name| name.starts_with("TLS13_"))
+        );
+
+        let resolved = tls(
+            "{cert: '', key: '', profile: custom, min_version: '1.3', ciphers: [TLS13_AES_128_GCM_SHA256], alpn: [http/1.1]}",
+        )
+        .unwrap();
+        assert_eq!(resolved.versions, vec![TlsVersion::Tls13]);
+        assert_eq!(resolved.ciphers, vec!["TLS13_AES_128_GCM_SHA256"]);
+        assert_eq!(resolved.alpn, vec!["http/1.1"]);
+
+        // versions and ciphers need the custom profile
+        let errors = tls("{cert: '', key: '', min_version: '1.3'}").unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+        // an unsupported version, a TLS 1.2 suite with TLS 1.3 only, leaving TLS 1.3 without
+        // a suite, and an unknown protocol
+        let errors = tls("{cert: '', key: '', profile: custom, min_version: '1.1'}").unwrap_err();
+        assert_eq!(errors.iter().count(), 1);
+        let errors = tls(
+            "{cert: '', key: '', profile: custom, min_version: '1.3', ciphers: [TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256], alpn: [spdy/3]}",
+        )
+        .unwrap_err();
+        assert_eq!(errors.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_server_cert() {
+        let yaml = r#"
+global:
+  port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    cert: ./fixtures/certs/backend.crt
+    key: ./fixtures/certs/backend.key
+  - server_name: [api.acme.com]
+    upstream: backend
+    cert: ./fixtures/certs/backend.crt
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        // a certificate without global.tls to fall back to, and one without its key
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        assert_eq!(errors.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_listeners() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+  listeners:
+    - port: 80
+    - name: public
+      address: "[::]"
+      port: 443
+      tls: true
+    - name: internal
+      address: 10.0.0.1
+      port: 8080
+      http2: true
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    listeners: ["0.0.0.0:80", public]
+  - server_name: [admin.acme.com]
+    upstream: backend
+    listeners: [internal]
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let listeners = &resolved.global.listeners;
+        assert_eq!(
+            listeners
+                .iter()
+                .map(|l| l.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["0.0.0.0:80", "public", "internal"]
+        );
+        assert_eq!(listeners[1].addr, ListenerAddr::Inet("[::]:443".parse()?));
+        assert!(listeners[1].tls && listeners[1].http2 && !listeners[1].ipv6_only);
+        assert!(!listeners[2].tls && listeners[2].http2);
+        let admin = &resolved.servers["admin.acme.com"];
+        assert!(admin.listens_on("internal") && !admin.listens_on("public"));
+
+        // the dual-stack listener takes port 443 on IPv4 too
+        let yaml = r#"
+global:
+  listeners:
+    - address: "::"
+      port: 443
+    - address: 127.0.0.1
+      port: 443
+    - address: 127.0.0.1
+      port: 80
+      tls: true
+      ipv6_only: true
+    - address: localhost
+      port: 80
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    listeners: [public]
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Invalid { location, .. } => location.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "global.listeners[2].ipv6_only",
+                "global.listeners[2].tls",
+                "global.listeners[3].address",
+                "global.listeners[1]",
+                "servers[0].listeners",
+            ]
+        );
+
+        // port and listeners are exclusive
+        let yaml = "global:\n  port: 80\n  listeners: [{port: 443}]\nservers: [{server_name: [acme.com], upstream: backend}]\nupstreams: [{name: backend, servers: ['127.0.0.1:3001']}]\n";
+        let config: SimpleProxyConfig = yaml.parse()?;
+        assert!(SimpleProxyConfigResolved::try_from(config).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unix_sockets() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  listeners:
+    - address: unix:/run/simple-proxy/http.sock
+      permissions: "660"
+      http2: true
+    - port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["unix:/run/app.sock", "127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let listener = &resolved.global.listeners[0];
+        assert_eq!(listener.name, "unix:/run/simple-proxy/http.sock");
+        assert_eq!(
+            listener.addr,
+            ListenerAddr::Unix(PathBuf::from("/run/simple-proxy/http.sock"))
+        );
+        assert_eq!(listener.permissions, Some(0o660));
+        let servers = &resolved.servers["acme.com"].upstream.servers;
+        assert_eq!(servers[0].unix_path(), Some("/run/app.sock"));
+        assert_eq!(servers[1].unix_path(), None);
+
+        let yaml = r#"
+global:
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+  listeners:
+    - address: unix:run/http.sock
+      port: 80
+      tls: true
+    - address: unix:/run/http.sock
+      permissions: "rw"
+    - port: 80
+      permissions: "660"
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["unix:", "unix:/run/app.sock"]
+    algorithm: ketama
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Invalid { location, .. } => location.as_str(),
+                ConfigError::InvalidAddress { addr, .. } => addr.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "global.listeners[0].address",
+                "global.listeners[0].port",
+                "global.listeners[0].tls",
+                "global.listeners[1].permissions",
+                "global.listeners[2].permissions",
+                "unix:",
+                "upstream `backend`.algorithm",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_protocol() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  listeners:
+    - port: 80
+      proxy_protocol: required
+    - port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+    proxy_protocol: v2
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let listeners = &resolved.global.listeners;
+        assert_eq!(listeners[0].proxy_protocol, ProxyProtocolMode::Required);
+        assert_eq!(listeners[1].proxy_protocol, ProxyProtocolMode::None);
+        let upstream = &resolved.servers["acme.com"].upstream;
+        assert_eq!(upstream.proxy_protocol, Some(ProxyProtocolVersion::V2));
+
+        let yaml = "global:\n  listeners: [{address: 'unix:/run/http.sock', proxy_protocol: optional}]\nservers: [{server_name: [acme.com], upstream: backend}]\nupstreams: [{name: backend, servers: ['127.0.0.1:3001']}]\n";
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::Invalid { location, .. } if location == "global.listeners[0].proxy_protocol"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_rewrite() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    request_headers:
+      set:
+        X-Request-Id: $request_id
+      remove: [X-Debug]
+    response_headers:
+      add:
+        Via: "1.1 ${host}"
+      remove_matching: ["^x-backend-"]
+    locations:
+      - path: /api/
+        upstream: backend
+        response_headers:
+          set:
+            Cache-Control: no-store
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let server = &resolved.servers["acme.com"];
+        assert_eq!(server.request_headers.set["x-request-id"], "$request_id");
+        assert_eq!(server.request_headers.remove, ["x-debug"]);
+        assert_eq!(server.response_headers.add["via"], "1.1 ${host}");
+        assert_eq!(server.response_headers.remove_matching, ["^x-backend-"]);
+        let location = &server.locations[0];
+        assert!(location.request_headers.is_empty());
+        assert_eq!(location.response_headers.set["cache-control"], "no-store");
+
+        let yaml = r#"
+global:
+  port: 8080
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    request_headers:
+      set:
+        X-Client: $client
+        X-Port: "$"
+        "Bad Name": x
+      add:
+        X-Line: "a\nb"
+    response_headers:
+      remove_matching: ["("]
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        let locations: Vec<&str> = errors
+            .iter()
+            .filter_map(|e| match e {
+                ConfigError::Invalid { location, .. } => Some(location.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            locations
+                .iter()
+                .filter(|l| **l == "servers[0].request_headers.set")
+                .count(),
+            3
+        );
+        assert!(locations.contains(&"servers[0].request_headers.add"));
+        assert!(locations.contains(&"servers[0].response_headers.remove_matching"));
+
+        let template: HeaderTemplate = "$scheme://${host}:$$".parse().unwrap();
+        assert_eq!(
+            template.0,
+            [
+                TemplatePart::Variable(HeaderVariable::Scheme),
+                TemplatePart::Literal("://".to_string()),
+                TemplatePart::Variable(HeaderVariable::Host),
+                TemplatePart::Literal(":$".to_string()),
+            ]
+        );
+        assert!("${host".parse::<HeaderTemplate>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_real_ip() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  port: 8080
+  real_ip:
+    trusted: [10.0.0.0/8, "2001:db8::1"]
+    header: X-Client-Ip
+    recursive: true
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let real_ip = resolved.global.real_ip.unwrap();
+        assert_eq!(real_ip.header, "x-client-ip");
+        assert!(real_ip.recursive);
+        assert!(real_ip.is_trusted("10.1.2.3".parse()?));
+        assert!(real_ip.is_trusted("::ffff:10.1.2.3".parse()?));
+        assert!(real_ip.is_trusted("2001:db8::1".parse()?));
+        assert!(!real_ip.is_trusted("2001:db8::2".parse()?));
+        assert!(!real_ip.is_trusted("11.0.0.1".parse()?));
+
+        let cidr: IpCidr = "0.0.0.0/0".parse().unwrap();
+        assert!(cidr.contains("192.0.2.1".parse()?));
+        assert!(!cidr.contains("2001:db8::1".parse()?));
+        assert_eq!(cidr.to_string(), "0.0.0.0/0");
+        assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+        assert!("10.0.0/8".parse::<IpCidr>().is_err());
+
+        let yaml = "global:\n  port: 8080\n  real_ip: {trusted: [10.0.0.1, ::1/129]}\nservers: [{server_name: [acme.com], upstream: backend}]\nupstreams: [{name: backend, servers: ['127.0.0.1:3001']}]\n";
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        assert!(errors.iter().any(|e| matches!(
+            e,
+            ConfigError::Invalid { location, .. } if location == "global.real_ip.trusted[1]"
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_to_https() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+    hsts:
+      max_age: 1day
+  listeners:
+    - name: http
+      port: 80
+      redirect_to_https: true
+    - name: https
+      port: 443
+      tls: true
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    redirect_to_https:
+      port: 8443
+      status: 308
+    hsts:
+      max_age: 2years
+      include_subdomains: true
+      preload: true
+  - server_name: [api.acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let resolved = SimpleProxyConfigResolved::try_from(config)?;
+        let redirect = resolved.global.listeners[0].redirect_to_https.unwrap();
+        assert_eq!((redirect.port, redirect.status), (443, 301));
+        let acme = &resolved.servers["acme.com"];
+        let redirect = acme.redirect_to_https.unwrap();
+        assert_eq!((redirect.port, redirect.status), (8443, 308));
+        assert_eq!(
+            acme.hsts.as_ref().unwrap().header_value(),
+            "max-age=63115200; includeSubDomains; preload"
         );
+        // the server without hsts of its own takes the one of global.tls
+        let api = &resolved.servers["api.acme.com"];
+        assert!(api.redirect_to_https.is_none());
+        assert_eq!(api.hsts.as_ref().unwrap().header_value(), "max-age=86400");
 
+        let yaml = r#"
+global:
+  listeners:
+    - port: 443
+      tls: true
+      redirect_to_https: true
+    - port: 80
+      redirect_to_https: {status: 200}
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+    redirect_to_https: false
+    hsts: {}
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let errors = SimpleProxyConfigResolved::try_from(config).unwrap_err();
+        let locations: Vec<_> = errors
+            .iter()
+            .map(|e| match e {
+                ConfigError::Invalid { location, .. } => location.as_str(),
+                _ => "",
+            })
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "global.listeners[0].tls",
+                "global.listeners[0].redirect_to_https",
+                "global.listeners[1].redirect_to_https.status",
+                "servers[0].hsts",
+            ]
+        );
         Ok(())
     }
+
+    #[test]
+    fn test_retry() {
+        let retry = |yaml: &str| {
+            let config: RetryConfig = serde_yaml::from_str(yaml).unwrap();
+            RetryResolved::try_from_with_location(&config, "retry")
+        };
+
+        assert_eq!(retry("{}").unwrap(), RetryResolved::default());
+        let resolved = retry(
+            "{attempts: 3, retry_on: [connect_failure, timeout], statuses: [502, 503], per_try_timeout: 2s}",
+        )
+        .unwrap();
+        assert_eq!(resolved.attempts, 3);
+        assert_eq!(
+            resolved.retry_on,
+            vec![RetryOn::ConnectFailure, RetryOn::Timeout]
+        );
+        assert_eq!(resolved.statuses, vec![502, 503]);
+        assert!(resolved.idempotent_only);
+        assert_eq!(resolved.per_try_timeout, Some(Duration::from_secs(2)));
+
+        let errors = retry("{attempts: 0, statuses: [42], budget: 1.5}").unwrap_err();
+        assert_eq!(errors.iter().count(), 3);
+
+        // upstreams without the block make a single attempt
+        let config: UpstreamConfig =
+            serde_yaml::from_str("{name: web_servers, servers: [\"127.0.0.1:3001\"]}").unwrap();
+        let upstream = UpstreamConfigResolved::try_from(&config).unwrap();
+        assert_eq!(upstream.retry.attempts, 1);
+    }
 }
@@ -1,25 +1,101 @@
-use clap::{Parser, arg};
-use pingora::{listeners::tls::TlsSettings, prelude::*, server::configuration::ServerConf};
-use simple_proxy::conf::ProxyConfig;
-use simple_proxy::proxy::{HealthCheck, SimpleProxy};
-use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum, arg};
+use pingora::{
+    apps::HttpServerOptions,
+    listeners::{TcpSocketOptions, tls::TlsSettings},
+    prelude::*,
+    proxy::http_proxy_service_with_name,
+    server::configuration::ServerConf,
+};
+use simple_proxy::conf::{
+    ConfigError, ListenerAddr, ListenerConfigResolved, ProxyConfig, SimpleProxyConfigResolved,
+};
+use simple_proxy::proxy::{
+    ConfigReloader, HealthCheck, RelayListeners, RouteTable, SimpleProxy, remove_stale_socket,
+};
+use std::{
+    fs::Permissions,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 use tracing::info;
 
 #[derive(Parser)]
 struct Args {
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     #[arg(default_value = "./fixtures/sample.yml")]
     config: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Start the proxy (default)
+    Run,
+    /// Load and resolve the config, exit non-zero if it is invalid
+    Check,
+    /// Print the resolved config
+    Dump {
+        #[arg(short, long, value_enum, default_value_t = DumpFormat::Yaml)]
+        format: DumpFormat,
+    },
+    /// Print the upstream and backends a request would be routed to
+    Route {
+        #[arg(long)]
+        host: String,
+        /// Path and query of the request
+        #[arg(long, default_value = "/")]
+        path: String,
+        #[arg(long, default_value = "GET")]
+        method: String,
+        /// Name of the listener the request comes in on, any listener by default
+        #[arg(long)]
+        listener: Option<String>,
+        /// Request header as `name: value`, may be repeated
+        #[arg(long = "header", short = 'H')]
+        headers: Vec<String>,
+    },
+}
+
+#[derive(Clone, ValueEnum)]
+enum DumpFormat {
+    Yaml,
+    Json,
 }
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
-    let config = ProxyConfig::load(args.config).unwrap();
+    match args.command.unwrap_or(Command::Run) {
+        Command::Run => run(args.config),
+        Command::Check => check(&args.config),
+        Command::Dump { format } => dump(&args.config, format),
+        Command::Route {
+            host,
+            path,
+            method,
+            listener,
+            headers,
+        } => route(
+            &args.config,
+            &host,
+            &path,
+            &method,
+            listener.as_deref(),
+            &headers,
+        ),
+    }
+}
 
+fn run(path: PathBuf) -> anyhow::Result<()> {
+    let config = ProxyConfig::load(&path)?;
+
+    let listeners = config.get().global.listeners.clone();
     let tls_conf = config.get().global.tls.clone();
 
     let server_conf = ServerConf {
+        // pingora's rustls connector verifies upstreams without a `ca` of their own against it
         ca_file: tls_conf.as_ref().and_then(|tls| tls.ca.clone()),
         ..Default::default()
     };
@@ -28,24 +104,149 @@ fn main() -> anyhow::Result<()> {
     let sp = SimpleProxy::try_new(config)?;
 
     let health_check = HealthCheck::new(sp.route_table().clone());
+    let reloader = ConfigReloader::new(path, sp.config().clone(), sp.route_table().clone());
 
-    let port = sp.config().get().global.port;
-    let proxy_addr = format!("0.0.0.0:{}", port);
-    let mut proxy = http_proxy_service(&my_server.configuration, sp);
+    let resolved = sp.config().get();
+    let relays = |listener: &ListenerConfigResolved| RelayListeners::relays(&resolved, listener);
+    let relay_listeners = if listeners.iter().any(relays) {
+        Some(RelayListeners::new(&resolved, sp.downstreams().clone())?)
+    } else {
+        None
+    };
+    let cert_reloader = relay_listeners
+        .as_ref()
+        .and_then(|relay_listeners| relay_listeners.cert_reloader(sp.config().clone()));
 
-    match tls_conf.as_ref() {
-        Some(tls) => {
-            let mut tls_settings = TlsSettings::intermediate(&tls.cert, &tls.key)?;
-            tls_settings.enable_h2();
-            proxy.add_tls_with_settings(&proxy_addr, None, tls_settings);
-            info!("proxy server started at https://{}", proxy_addr);
-        }
-        None => {
-            proxy.add_tcp(&proxy_addr);
-            info!("proxy server started at http://{}", proxy_addr);
+    // one proxy service per listener, so that each knows which servers it may route to
+    for listener in listeners.iter() {
+        let mut proxy = http_proxy_service_with_name(
+            &my_server.configuration,
+            sp.for_listener(listener),
+            &format!("proxy {}", listener.name),
+        );
+        let mut options = HttpServerOptions::default();
+        match &relay_listeners {
+            Some(relay_listeners) if relays(listener) => {
+                // the listener terminates tls and reads the PROXY protocol header, then relays
+                // plain http/1.1 and h2 to the proxy
+                let relay_listener = relay_listeners.listener(listener)?;
+                options.h2c = listener.tls || listener.http2;
+                proxy.add_uds(&relay_listener.proxy_path().to_string_lossy(), None);
+                my_server.add_service(relay_listener);
+                let scheme = if listener.tls { "https" } else { "http" };
+                info!("proxy server started at {}://{}", scheme, listener.addr);
+            }
+            _ => {
+                // with tls, h2 is negotiated in ALPN
+                options.h2c = listener.http2 && !listener.tls;
+                match &listener.addr {
+                    ListenerAddr::Inet(addr) => {
+                        let mut socket_options = TcpSocketOptions::default();
+                        if addr.is_ipv6() {
+                            socket_options.ipv6_only = Some(listener.ipv6_only);
+                        }
+                        match tls_conf.as_ref().filter(|_| listener.tls) {
+                            Some(tls) => {
+                                let mut tls_settings =
+                                    TlsSettings::intermediate(&tls.cert, &tls.key)?;
+                                if listener.http2 {
+                                    tls_settings.enable_h2();
+                                }
+                                proxy.add_tls_with_settings(
+                                    &addr.to_string(),
+                                    Some(socket_options),
+                                    tls_settings,
+                                );
+                            }
+                            None => proxy.add_tcp_with_settings(&addr.to_string(), socket_options),
+                        }
+                    }
+                    ListenerAddr::Unix(path) => {
+                        remove_stale_socket(path)?;
+                        let permissions = listener.permissions.map(Permissions::from_mode);
+                        proxy.add_uds(&path.to_string_lossy(), permissions);
+                    }
+                }
+                let scheme = if listener.tls { "https" } else { "http" };
+                info!("proxy server started at {}://{}", scheme, listener.addr);
+            }
         }
+        proxy.app_logic_mut().expect("proxy app").server_options = Some(options);
+        my_server.add_service(proxy);
     }
+    // the relay listeners own the directory of their sockets now, it is removed once they
+    // stop
+    drop(relay_listeners);
     my_server.add_service(health_check);
-    my_server.add_service(proxy);
+    my_server.add_service(reloader);
+    if let Some(cert_reloader) = cert_reloader {
+        my_server.add_service(cert_reloader);
+    }
     my_server.run_forever();
 }
+
+fn check(path: &Path) -> anyhow::Result<()> {
+    match SimpleProxyConfigResolved::load(path) {
+        Ok(_) => {
+            println!("{}: ok", path.display());
+            Ok(())
+        }
+        Err(errors) => {
+            for e in errors.iter() {
+                match e {
+                    ConfigError::At { .. } => eprintln!("{}", e),
+                    _ => eprintln!("{}: {}", path.display(), e),
+                }
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn dump(path: &Path, format: DumpFormat) -> anyhow::Result<()> {
+    let config = SimpleProxyConfigResolved::load(path)?;
+    let output = match format {
+        DumpFormat::Yaml => serde_yaml::to_string(&config)?,
+        DumpFormat::Json => serde_json::to_string_pretty(&config)?,
+    };
+    println!("{}", output);
+    Ok(())
+}
+
+fn route(
+    path: &Path,
+    host: &str,
+    uri: &str,
+    method: &str,
+    listener: Option<&str>,
+    headers: &[String],
+) -> anyhow::Result<()> {
+    let config = SimpleProxyConfigResolved::load(path)?;
+    let route_table = RouteTable::new(&config)?;
+
+    let mut req = RequestHeader::build(method.to_ascii_uppercase().as_str(), uri.as_bytes(), None)?;
+    for header in headers {
+        let Some((name, value)) = header.split_once(':') else {
+            anyhow::bail!("invalid header `{}`, expected `name: value`", header);
+        };
+        req.append_header(name.trim().to_string(), value.trim())?;
+    }
+    let Some(entry) = route_table.lookup(host, &req, listener) else {
+        eprintln!("no upstream found for {} {}{}", method, host, uri);
+        std::process::exit(1);
+    };
+
+    println!("request: {} {}{}", method, host, uri);
+    println!("upstream: {}", entry.config.name);
+    println!("tls: {}", entry.tls);
+    println!("backends:");
+    for backend in entry.upstream.primary().get_backend().iter() {
+        println!("  - {} (weight {})", backend.addr, backend.weight);
+    }
+    if let Some(backup) = entry.upstream.backup() {
+        for backend in backup.get_backend().iter() {
+            println!("  - {} (weight {}, backup)", backend.addr, backend.weight);
+        }
+    }
+    Ok(())
+}
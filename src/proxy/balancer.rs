@@ -0,0 +1,496 @@
+use std::{
+    collections::{BTreeSet, HashMap as StdHashMap},
+    net::{SocketAddr as InetSocketAddr, ToSocketAddrs},
+    os::unix::net::SocketAddr as UnixSocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Context;
+use futures::FutureExt;
+use papaya::HashMap;
+use pingora_core::protocols::l4::socket::SocketAddr;
+use pingora_load_balancing::{
+    Backend, Backends, Extensions, LoadBalancer,
+    discovery::Static,
+    selection::{BackendIter, BackendSelection, Consistent, FNVHash, Random, RoundRobin},
+};
+
+use crate::{
+    conf::{LoadBalanceAlgorithm, UpstreamConfigResolved, UpstreamServerResolved},
+    proxy::{
+        health::health_check,
+        outlier::{Outcome, OutlierDetector},
+        retry::RetryBudget,
+        tls::UpstreamTls,
+    },
+};
+
+const MAX_BACKEND_ITER: usize = 32;
+
+/// Load balancer of an upstream. Backup servers are kept in a pool of their own, which is
+/// only selected from when no primary server is healthy.
+pub struct Balancer {
+    primary: Pool,
+    backup: Option<Pool>,
+    outliers: Option<OutlierDetector>,
+    retry_budget: RetryBudget,
+    tls: Option<UpstreamTls>,
+}
+
+/// The addresses the `host:port` of upstream servers resolve to. They are looked up before
+/// the route table is built, so that building it never waits for DNS.
+#[derive(Debug, Default)]
+pub struct ResolvedAddrs(StdHashMap<String, Vec<InetSocketAddr>>);
+
+/// A set of backends behind the selection algorithm the upstream is configured with.
+enum Pool {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    Random(LoadBalancer<Random>),
+    Ketama(LoadBalancer<Consistent>),
+    FnvHash(LoadBalancer<FNVHash>),
+    LeastConn(LeastConn),
+}
+
+/// Picks the healthy backend with the fewest in flight requests relative to its weight. The
+/// wrapped round robin balancer only provides discovery and health checks.
+struct LeastConn {
+    lb: LoadBalancer<RoundRobin>,
+    conns: HashMap<Backend, AtomicUsize>,
+}
+
+macro_rules! dispatch {
+    ($self:expr, $lb:ident => $body:expr) => {
+        match $self {
+            Pool::RoundRobin($lb) => $body,
+            Pool::Random($lb) => $body,
+            Pool::Ketama($lb) => $body,
+            Pool::FnvHash($lb) => $body,
+            Pool::LeastConn(LeastConn { lb: $lb, .. }) => $body,
+        }
+    };
+}
+
+impl Balancer {
+    /// `tls` is whether the servers are reached over TLS, probes follow it. The addresses of
+    /// the servers are taken from `addrs`.
+    pub fn new(
+        config: &UpstreamConfigResolved,
+        tls: bool,
+        addrs: &ResolvedAddrs,
+    ) -> anyhow::Result<Self> {
+        let upstream_tls = config
+            .upstream_tls
+            .as_ref()
+            .map(UpstreamTls::new)
+            .transpose()?;
+        let (backup, primary): (Vec<_>, Vec<_>) =
+            config.servers.iter().partition(|server| server.backup);
+        let backup = if backup.is_empty() {
+            None
+        } else {
+            Some(Pool::new(
+                config,
+                &backup,
+                addrs,
+                tls,
+                upstream_tls.as_ref(),
+            )?)
+        };
+        Ok(Self {
+            primary: Pool::new(config, &primary, addrs, tls, upstream_tls.as_ref())?,
+            backup,
+            outliers: config
+                .outlier_detection
+                .as_ref()
+                .map(|outliers| OutlierDetector::new(&config.name, outliers)),
+            retry_budget: RetryBudget::new(config.retry.budget),
+            tls: upstream_tls,
+        })
+    }
+
+    pub async fn update(&self) -> anyhow::Result<()> {
+        self.primary.update().await?;
+        if let Some(backup) = &self.backup {
+            backup.update().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn run_health_check(&self, parallel: bool) {
+        self.primary.backends().run_health_check(parallel).await;
+        if let Some(backup) = &self.backup {
+            backup.backends().run_health_check(parallel).await;
+        }
+    }
+
+    pub fn primary(&self) -> &Backends {
+        self.primary.backends()
+    }
+
+    pub fn backup(&self) -> Option<&Backends> {
+        self.backup.as_ref().map(|backup| backup.backends())
+    }
+
+    /// Select a healthy backend that is not ejected, falling back to the backup servers. `key`
+    /// is only used by the hashing algorithms. Backends in `tried` are only selected again
+    /// if no other is left. With `least_conn` the backend must be handed back with
+    /// [Balancer::release] once the request is done.
+    pub fn select(&self, key: &[u8], tried: &[Backend]) -> Option<Backend> {
+        let available = |backend: &Backend| {
+            self.outliers
+                .as_ref()
+                .is_none_or(|outliers| !outliers.is_ejected(backend))
+        };
+        let select = |accept: &dyn Fn(&Backend) -> bool| {
+            self.primary
+                .select(key, accept)
+                .or_else(|| self.backup.as_ref()?.select(key, accept))
+        };
+        select(&|backend| available(backend) && !tried.contains(backend)).or_else(|| {
+            if tried.is_empty() {
+                None
+            } else {
+                select(&available)
+            }
+        })
+    }
+
+    pub(crate) fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    pub(crate) fn tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    /// Feed the outcome of a request to the outlier detection.
+    pub fn report(&self, backend: &Backend, outcome: Outcome) {
+        if let Some(outliers) = &self.outliers {
+            let total = self.primary().get_backend().len()
+                + self.backup().map_or(0, |backup| backup.get_backend().len());
+            outliers.report(backend, outcome, total);
+        }
+    }
+
+    pub fn release(&self, backend: &Backend) {
+        self.primary.release(backend);
+        if let Some(backup) = &self.backup {
+            backup.release(backend);
+        }
+    }
+}
+
+impl Pool {
+    fn new(
+        config: &UpstreamConfigResolved,
+        servers: &[&UpstreamServerResolved],
+        addrs: &ResolvedAddrs,
+        tls: bool,
+        upstream_tls: Option<&UpstreamTls>,
+    ) -> anyhow::Result<Self> {
+        let tls = (tls, upstream_tls);
+        Ok(match config.algorithm {
+            LoadBalanceAlgorithm::RoundRobin => {
+                Self::RoundRobin(load_balancer(config, servers, addrs, tls)?)
+            }
+            LoadBalanceAlgorithm::Random => {
+                Self::Random(load_balancer(config, servers, addrs, tls)?)
+            }
+            LoadBalanceAlgorithm::Ketama => {
+                Self::Ketama(load_balancer(config, servers, addrs, tls)?)
+            }
+            LoadBalanceAlgorithm::FnvHash => {
+                Self::FnvHash(load_balancer(config, servers, addrs, tls)?)
+            }
+            LoadBalanceAlgorithm::LeastConn => Self::LeastConn(LeastConn {
+                lb: load_balancer(config, servers, addrs, tls)?,
+                conns: HashMap::new(),
+            }),
+        })
+    }
+
+    async fn update(&self) -> anyhow::Result<()> {
+        dispatch!(self, lb => lb.update().await)?;
+        Ok(())
+    }
+
+    fn backends(&self) -> &Backends {
+        dispatch!(self, lb => lb.backends())
+    }
+
+    fn select(&self, key: &[u8], accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        match self {
+            Self::LeastConn(least_conn) => least_conn.select(accept),
+            _ => dispatch!(self, lb => lb.select_with(key, MAX_BACKEND_ITER, |backend, health| {
+                health && accept(backend)
+            })),
+        }
+    }
+
+    fn release(&self, backend: &Backend) {
+        if let Self::LeastConn(least_conn) = self {
+            least_conn.release(backend);
+        }
+    }
+}
+
+impl LeastConn {
+    fn select(&self, accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let backends = self.lb.backends();
+        let conns = self.conns.pin();
+        let count = |backend: &Backend| {
+            conns
+                .get(backend)
+                .map_or(0, |count| count.load(Ordering::Relaxed))
+        };
+        // a / wa < b / wb without dividing
+        let backend = backends
+            .get_backend()
+            .iter()
+            .filter(|backend| backends.ready(backend) && accept(backend))
+            .min_by(|a, b| (count(a) * b.weight).cmp(&(count(b) * a.weight)))?
+            .clone();
+        conns
+            .get_or_insert_with(backend.clone(), || AtomicUsize::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+        Some(backend)
+    }
+
+    fn release(&self, backend: &Backend) {
+        if let Some(count) = self.conns.pin().get(backend) {
+            // never wraps below zero, even if a backend is released twice
+            count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .ok();
+        }
+    }
+}
+
+impl ResolvedAddrs {
+    /// Look the addresses of the servers of `upstreams` up, blocking the thread. Only for use
+    /// outside the async runtime, i.e. at startup and in commands.
+    pub fn resolve<'a>(
+        upstreams: impl IntoIterator<Item = &'a UpstreamConfigResolved>,
+    ) -> anyhow::Result<Self> {
+        let mut addrs = StdHashMap::new();
+        for addr in inet_addrs(upstreams) {
+            let resolved = addr
+                .to_socket_addrs()
+                .with_context(|| format!("failed to resolve {}", addr))?;
+            addrs.insert(addr.to_string(), resolved.collect::<Vec<_>>());
+        }
+        Ok(Self(addrs))
+    }
+
+    /// Look the addresses of the servers of `upstreams` up without blocking the runtime.
+    pub async fn lookup<'a>(
+        upstreams: impl IntoIterator<Item = &'a UpstreamConfigResolved>,
+    ) -> anyhow::Result<Self> {
+        let lookups = inet_addrs(upstreams).into_iter().map(|addr| async move {
+            let resolved = tokio::net::lookup_host(addr)
+                .await
+                .with_context(|| format!("failed to resolve {}", addr))?;
+            anyhow::Ok((addr.to_string(), resolved.collect::<Vec<_>>()))
+        });
+        Ok(Self(
+            futures::future::try_join_all(lookups)
+                .await?
+                .into_iter()
+                .collect(),
+        ))
+    }
+
+    fn get(&self, addr: &str) -> anyhow::Result<&[InetSocketAddr]> {
+        self.0
+            .get(addr)
+            .map(Vec::as_slice)
+            .with_context(|| format!("{} was not resolved", addr))
+    }
+}
+
+// the `host:port` of servers not on a unix socket, each once
+fn inet_addrs<'a>(
+    upstreams: impl IntoIterator<Item = &'a UpstreamConfigResolved>,
+) -> BTreeSet<&'a str> {
+    upstreams
+        .into_iter()
+        .flat_map(|upstream| upstream.servers.iter())
+        .filter(|server| server.unix_path().is_none())
+        .map(|server| server.addr.as_str())
+        .collect()
+}
+
+fn load_balancer<S>(
+    config: &UpstreamConfigResolved,
+    servers: &[&UpstreamServerResolved],
+    addrs: &ResolvedAddrs,
+    (tls, upstream_tls): (bool, Option<&UpstreamTls>),
+) -> anyhow::Result<LoadBalancer<S>>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let mut backends = BTreeSet::new();
+    for server in servers {
+        if let Some(path) = server.unix_path() {
+            backends.insert(Backend {
+                addr: SocketAddr::Unix(UnixSocketAddr::from_pathname(path)?),
+                weight: server.weight,
+                ext: Extensions::new(),
+            });
+            continue;
+        }
+        // a hostname may resolve to several addresses, each gets the weight of the server
+        for addr in addrs.get(&server.addr)? {
+            backends.insert(Backend::new_with_weight(&addr.to_string(), server.weight)?);
+        }
+    }
+    let mut lb = LoadBalancer::from_backends(Backends::new(Static::new(backends)));
+    lb.update()
+        .now_or_never()
+        .context("static discovery did not complete")??;
+
+    lb.set_health_check(health_check(
+        &config.health_check,
+        tls,
+        upstream_tls,
+        config.proxy_protocol,
+    ));
+    lb.health_check_frequency = Some(config.health_check.interval);
+    Ok(lb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::{HealthCheckResolved, RetryResolved, UpstreamTimeouts};
+
+    fn server(addr: &str, weight: usize, backup: bool) -> UpstreamServerResolved {
+        UpstreamServerResolved {
+            addr: addr.to_string(),
+            weight,
+            backup,
+        }
+    }
+
+    fn upstream(algorithm: LoadBalanceAlgorithm) -> UpstreamConfigResolved {
+        UpstreamConfigResolved {
+            name: "web_servers".to_string(),
+            servers: vec![
+                server("127.0.0.1:3001", 1, false),
+                server("127.0.0.1:3002", 1, false),
+            ],
+            algorithm,
+            hash_key: None,
+            health_check: HealthCheckResolved::default(),
+            outlier_detection: None,
+            retry: RetryResolved::default(),
+            timeouts: UpstreamTimeouts::default(),
+            upstream_tls: None,
+            proxy_protocol: None,
+        }
+    }
+
+    fn balancer(config: &UpstreamConfigResolved) -> anyhow::Result<Balancer> {
+        Balancer::new(config, false, &ResolvedAddrs::resolve([config])?)
+    }
+
+    #[test]
+    fn test_hashing_is_sticky() -> anyhow::Result<()> {
+        for algorithm in [LoadBalanceAlgorithm::Ketama, LoadBalanceAlgorithm::FnvHash] {
+            let balancer = balancer(&upstream(algorithm))?;
+            let first = balancer.select(b"10.0.0.1", &[]);
+            assert!(first.is_some());
+            for _ in 0..10 {
+                assert_eq!(balancer.select(b"10.0.0.1", &[]), first);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_least_conn() -> anyhow::Result<()> {
+        let balancer = balancer(&upstream(LoadBalanceAlgorithm::LeastConn))?;
+        let a = balancer.select(b"", &[]).unwrap();
+        let b = balancer.select(b"", &[]).unwrap();
+        assert_ne!(a, b);
+
+        // a is free again, so it is picked over the busy b
+        balancer.release(&a);
+        assert_eq!(balancer.select(b"", &[]), Some(a));
+        Ok(())
+    }
+
+    #[test]
+    fn test_weight_and_backup() -> anyhow::Result<()> {
+        let mut config = upstream(LoadBalanceAlgorithm::RoundRobin);
+        config.servers = vec![
+            server("127.0.0.1:3001", 3, false),
+            server("127.0.0.1:3002", 1, false),
+            server("127.0.0.1:3003", 1, true),
+        ];
+        let balancer = balancer(&config)?;
+
+        let mut counts = std::collections::HashMap::new();
+        for _ in 0..8 {
+            let backend = balancer.select(b"", &[]).unwrap();
+            *counts.entry(backend.addr.to_string()).or_insert(0) += 1;
+        }
+        assert_eq!(counts.get("127.0.0.1:3001"), Some(&6));
+        assert_eq!(counts.get("127.0.0.1:3002"), Some(&2));
+        assert_eq!(counts.get("127.0.0.1:3003"), None);
+
+        // the backup takes over once every primary is down
+        for backend in balancer.primary().get_backend().iter() {
+            balancer.primary().set_enable(backend, false);
+        }
+        let backend = balancer.select(b"", &[]).unwrap();
+        assert_eq!(backend.addr.to_string(), "127.0.0.1:3003");
+        Ok(())
+    }
+
+    #[test]
+    fn test_unix_servers() -> anyhow::Result<()> {
+        let mut config = upstream(LoadBalanceAlgorithm::RoundRobin);
+        config.servers = vec![
+            server("unix:/run/app-1.sock", 1, false),
+            server("unix:/run/app-2.sock", 1, false),
+        ];
+        let balancer = balancer(&config)?;
+        let backend = balancer.select(b"", &[]).unwrap();
+        let path = backend.addr.as_unix().and_then(|addr| addr.as_pathname());
+        assert!(path.is_some_and(|path| path.starts_with("/run")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_skips_tried() -> anyhow::Result<()> {
+        let balancer = balancer(&upstream(LoadBalanceAlgorithm::Ketama))?;
+        let first = balancer.select(b"10.0.0.1", &[]).unwrap();
+        let second = balancer.select(b"10.0.0.1", &[first.clone()]).unwrap();
+        assert_ne!(first, second);
+
+        // every backend was tried, one is picked again rather than none
+        assert!(balancer.select(b"10.0.0.1", &[first, second]).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolved_addrs() -> anyhow::Result<()> {
+        let mut config = upstream(LoadBalanceAlgorithm::RoundRobin);
+        config.servers = vec![
+            server("localhost:3001", 1, false),
+            server("127.0.0.1:3002", 1, false),
+            server("unix:/run/app.sock", 1, false),
+        ];
+        let addrs = ResolvedAddrs::lookup([&config]).await?;
+        assert!(!addrs.get("localhost:3001")?.is_empty());
+        assert_eq!(addrs.get("127.0.0.1:3002")?, ["127.0.0.1:3002".parse()?]);
+        assert!(addrs.get("unix:/run/app.sock").is_err());
+        assert!(Balancer::new(&config, false, &addrs).is_ok());
+
+        // servers missing from the addresses are an error rather than a lookup
+        assert!(Balancer::new(&config, false, &ResolvedAddrs::default()).is_err());
+        Ok(())
+    }
+}
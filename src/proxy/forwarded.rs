@@ -0,0 +1,238 @@
+use std::net::IpAddr;
+
+use pingora::{http::RequestHeader, prelude::*};
+
+use crate::conf::{ForwardedHeadersResolved, ForwardedMode};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PORT: &str = "x-forwarded-port";
+const X_REAL_IP: &str = "x-real-ip";
+const FORWARDED: &str = "forwarded";
+
+/// What the forwarding headers tell the upstream about the request the client sent.
+pub(crate) struct ForwardedRequest<'a> {
+    /// the client behind the trusted proxies of `real_ip`, `None` for clients on a unix socket
+    pub client_ip: Option<IpAddr>,
+    /// the other end of the connection, what the lists are appended with
+    pub peer_ip: Option<IpAddr>,
+    pub tls: bool,
+    /// the host as sent by the client, with the port if it had one
+    pub host: Option<&'a str>,
+    /// what the client connected to
+    pub port: u16,
+}
+
+impl ForwardedRequest<'_> {
+    /// Set the headers turned on in `config`. Values the client sent are replaced, except the
+    /// lists of `X-Forwarded-For` and `Forwarded` in `append` mode, which get the peer the
+    /// request came from; headers turned off are passed on as received.
+    pub fn apply(&self, config: &ForwardedHeadersResolved, req: &mut RequestHeader) -> Result<()> {
+        let proto = if self.tls { "https" } else { "http" };
+        let client_ip = self.client_ip.map(|ip| ip.to_string());
+        let node = |mode| match mode {
+            ForwardedMode::Append => self.peer_ip,
+            _ => self.client_ip,
+        };
+        set_list(
+            req,
+            X_FORWARDED_FOR,
+            config.x_forwarded_for,
+            node(config.x_forwarded_for).map(|ip| ip.to_string()),
+        )?;
+        set_list(
+            req,
+            FORWARDED,
+            config.forwarded,
+            Some(self.forwarded_element(node(config.forwarded), proto)),
+        )?;
+        if config.x_forwarded_proto {
+            req.insert_header(X_FORWARDED_PROTO, proto)?;
+        }
+        if config.x_forwarded_host {
+            set(req, X_FORWARDED_HOST, self.host.map(str::to_string))?;
+        }
+        if config.x_forwarded_port {
+            req.insert_header(X_FORWARDED_PORT, self.port.to_string())?;
+        }
+        if config.x_real_ip {
+            set(req, X_REAL_IP, client_ip)?;
+        }
+        Ok(())
+    }
+
+    // `for=192.0.2.1;proto=https;host=acme.com`, IPv6 addresses are quoted in brackets
+    fn forwarded_element(&self, ip: Option<IpAddr>, proto: &str) -> String {
+        let node = match ip {
+            Some(IpAddr::V4(ip)) => ip.to_string(),
+            Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+            None => "unknown".to_string(),
+        };
+        let mut element = format!("for={};proto={}", node, proto);
+        if let Some(host) = self.host {
+            element.push_str(";host=");
+            element.push_str(&quote(host));
+        }
+        element
+    }
+}
+
+// the header set to `value`, or removed without one
+fn set(req: &mut RequestHeader, name: &'static str, value: Option<String>) -> Result<()> {
+    match value {
+        Some(value) => req.insert_header(name, value)?,
+        None => {
+            req.remove_header(name);
+        }
+    }
+    Ok(())
+}
+
+// `X-Forwarded-For` and `Forwarded` have an element per proxy the request went through, the
+// header lines the client sent are joined into one
+fn set_list(
+    req: &mut RequestHeader,
+    name: &'static str,
+    mode: ForwardedMode,
+    element: Option<String>,
+) -> Result<()> {
+    let received = match mode {
+        ForwardedMode::Off => return Ok(()),
+        ForwardedMode::Append => {
+            let values: Vec<&str> = req
+                .headers
+                .get_all(name)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .collect();
+            (!values.is_empty()).then(|| values.join(", "))
+        }
+        ForwardedMode::Replace => None,
+    };
+    let value = match (received, element) {
+        (Some(received), Some(element)) => Some(format!("{}, {}", received, element)),
+        (received, element) => received.or(element),
+    };
+    set(req, name, value)
+}
+
+// a token as is, anything else as a quoted string
+fn quote(value: &str) -> String {
+    let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(token) {
+        return value.to_string();
+    }
+    let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&'static str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(*name, *value).unwrap();
+        }
+        req
+    }
+
+    fn header<'a>(req: &'a RequestHeader, name: &str) -> Option<&'a str> {
+        req.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    #[test]
+    fn test_forwarded_headers() {
+        let forwarded = ForwardedRequest {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            peer_ip: Some("192.0.2.1".parse().unwrap()),
+            tls: true,
+            host: Some("acme.com:8443"),
+            port: 8443,
+        };
+        let mut req = request(&[
+            ("x-forwarded-for", "10.0.0.1"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("x-real-ip", "10.0.0.1"),
+            ("forwarded", "for=10.0.0.1"),
+        ]);
+        let config = ForwardedHeadersResolved {
+            forwarded: ForwardedMode::Append,
+            ..Default::default()
+        };
+        forwarded.apply(&config, &mut req).unwrap();
+        assert_eq!(
+            header(&req, "x-forwarded-for"),
+            Some("10.0.0.1, 10.0.0.2, 192.0.2.1")
+        );
+        assert_eq!(header(&req, "x-forwarded-proto"), Some("https"));
+        assert_eq!(header(&req, "x-forwarded-host"), Some("acme.com:8443"));
+        assert_eq!(header(&req, "x-forwarded-port"), Some("8443"));
+        assert_eq!(header(&req, "x-real-ip"), Some("192.0.2.1"));
+        assert_eq!(
+            header(&req, "forwarded"),
+            Some("for=10.0.0.1, for=192.0.2.1;proto=https;host=\"acme.com:8443\"")
+        );
+
+        // replace drops what the client sent, off passes it on
+        let mut req = request(&[("x-forwarded-for", "10.0.0.1"), ("x-real-ip", "10.0.0.1")]);
+        let config = ForwardedHeadersResolved {
+            x_forwarded_for: ForwardedMode::Replace,
+            x_real_ip: false,
+            ..Default::default()
+        };
+        forwarded.apply(&config, &mut req).unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), Some("192.0.2.1"));
+        assert_eq!(header(&req, "x-real-ip"), Some("10.0.0.1"));
+        assert_eq!(header(&req, "forwarded"), None);
+
+        // behind a trusted proxy the list gets the proxy, replace starts over with the client
+        let forwarded = ForwardedRequest {
+            peer_ip: Some("10.0.0.2".parse().unwrap()),
+            ..forwarded
+        };
+        let mut req = request(&[("x-forwarded-for", "192.0.2.1")]);
+        forwarded
+            .apply(&ForwardedHeadersResolved::default(), &mut req)
+            .unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), Some("192.0.2.1, 10.0.0.2"));
+        assert_eq!(header(&req, "x-real-ip"), Some("192.0.2.1"));
+        forwarded.apply(&config, &mut req).unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), Some("192.0.2.1"));
+    }
+
+    #[test]
+    fn test_forwarded_element() {
+        let forwarded = ForwardedRequest {
+            client_ip: Some("2001:db8::1".parse().unwrap()),
+            peer_ip: Some("2001:db8::1".parse().unwrap()),
+            tls: false,
+            host: Some("acme.com"),
+            port: 80,
+        };
+        assert_eq!(
+            forwarded.forwarded_element(forwarded.client_ip, "http"),
+            "for=\"[2001:db8::1]\";proto=http;host=acme.com"
+        );
+
+        // clients on a unix socket
+        let forwarded = ForwardedRequest {
+            client_ip: None,
+            peer_ip: None,
+            host: None,
+            ..forwarded
+        };
+        assert_eq!(
+            forwarded.forwarded_element(None, "http"),
+            "for=unknown;proto=http"
+        );
+        let mut req = request(&[("x-real-ip", "10.0.0.1")]);
+        forwarded
+            .apply(&ForwardedHeadersResolved::default(), &mut req)
+            .unwrap();
+        assert_eq!(header(&req, "x-forwarded-for"), None);
+        assert_eq!(header(&req, "x-real-ip"), None);
+    }
+}
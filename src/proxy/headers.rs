@@ -0,0 +1,259 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use axum::http::{HeaderMap, HeaderValue};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    prelude::*,
+};
+use regex::Regex;
+use tracing::warn;
+
+use crate::conf::{HeaderRewriteResolved, HeaderTemplate, HeaderVariable, TemplatePart};
+
+/// The `request_headers` or `response_headers` of a route, the server's followed by the
+/// location's.
+#[derive(Debug, Default)]
+pub(crate) struct HeaderRewrite {
+    rules: Vec<Rules>,
+}
+
+#[derive(Debug)]
+struct Rules {
+    remove_matching: Vec<Regex>,
+    remove: Vec<String>,
+    set: Vec<(String, HeaderTemplate)>,
+    add: Vec<(String, HeaderTemplate)>,
+}
+
+/// What the variables of header values expand to for a request.
+pub(crate) struct HeaderVars<'a> {
+    /// `None` for clients on a unix socket
+    pub client_ip: Option<IpAddr>,
+    pub host: &'a str,
+    pub tls: bool,
+    pub request_id: &'a str,
+    /// `None` until a backend is selected
+    pub upstream_addr: Option<String>,
+}
+
+/// Request and response headers, pingora keeps the case of the names next to the map.
+pub(crate) trait Headers {
+    fn map(&self) -> &HeaderMap;
+    fn insert(&mut self, name: &str, value: HeaderValue) -> Result<()>;
+    fn append(&mut self, name: &str, value: HeaderValue) -> Result<()>;
+    fn remove(&mut self, name: &str);
+}
+
+impl HeaderRewrite {
+    pub fn new(configs: &[&HeaderRewriteResolved]) -> anyhow::Result<Self> {
+        let templates = |values: &BTreeMap<String, String>| {
+            values
+                .iter()
+                .map(|(name, value)| {
+                    let template = value
+                        .parse::<HeaderTemplate>()
+                        .map_err(anyhow::Error::msg)?;
+                    Ok((name.clone(), template))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        let mut rules = Vec::new();
+        for config in configs.iter().filter(|config| !config.is_empty()) {
+            rules.push(Rules {
+                remove_matching: config
+                    .remove_matching
+                    .iter()
+                    .map(|pattern| Regex::new(pattern))
+                    .collect::<Result<_, _>>()?,
+                remove: config.remove.clone(),
+                set: templates(&config.set)?,
+                add: templates(&config.add)?,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// Make the changes of each block in the order `remove_matching`, `remove`, `set` and
+    /// `add`. A value that expands to something a header cannot hold is skipped.
+    pub fn apply(&self, headers: &mut impl Headers, vars: &HeaderVars) -> Result<()> {
+        for rules in self.rules.iter() {
+            let matching: Vec<String> = headers
+                .map()
+                .keys()
+                .map(|name| name.as_str())
+                .filter(|name| rules.remove_matching.iter().any(|re| re.is_match(name)))
+                .map(str::to_string)
+                .collect();
+            for name in matching.iter().chain(rules.remove.iter()) {
+                headers.remove(name);
+            }
+            for (name, template) in rules.set.iter() {
+                if let Some(value) = vars.expand(name, template) {
+                    headers.insert(name, value)?;
+                }
+            }
+            for (name, template) in rules.add.iter() {
+                if let Some(value) = vars.expand(name, template) {
+                    headers.append(name, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl HeaderVars<'_> {
+    fn expand(&self, name: &str, template: &HeaderTemplate) -> Option<HeaderValue> {
+        let mut value = String::new();
+        for part in template.0.iter() {
+            match part {
+                TemplatePart::Literal(literal) => value.push_str(literal),
+                TemplatePart::Variable(variable) => value.push_str(&self.get(*variable)),
+            }
+        }
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!(
+                    "header rewrite: skip invalid value of {}: {:?}",
+                    name, value
+                );
+                None
+            }
+        }
+    }
+
+    // empty for what the request does not have
+    fn get(&self, variable: HeaderVariable) -> String {
+        match variable {
+            HeaderVariable::ClientIp => self.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+            HeaderVariable::Host => self.host.to_string(),
+            HeaderVariable::Scheme => if self.tls { "https" } else { "http" }.to_string(),
+            HeaderVariable::RequestId => self.request_id.to_string(),
+            HeaderVariable::UpstreamAddr => self.upstream_addr.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl Headers for RequestHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: &str, value: HeaderValue) -> Result<()> {
+        self.insert_header(name.to_string(), value)
+    }
+
+    fn append(&mut self, name: &str, value: HeaderValue) -> Result<()> {
+        self.append_header(name.to_string(), value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.remove_header(name);
+    }
+}
+
+impl Headers for ResponseHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    fn insert(&mut self, name: &str, value: HeaderValue) -> Result<()> {
+        self.insert_header(name.to_string(), value)
+    }
+
+    fn append(&mut self, name: &str, value: HeaderValue) -> Result<()> {
+        self.append_header(name.to_string(), value).map(|_| ())
+    }
+
+    fn remove(&mut self, name: &str) {
+        self.remove_header(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(
+        set: &[(&str, &str)],
+        add: &[(&str, &str)],
+        remove: &[&str],
+        remove_matching: &[&str],
+    ) -> HeaderRewriteResolved {
+        let map = |values: &[(&str, &str)]| {
+            values
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        HeaderRewriteResolved {
+            set: map(set),
+            add: map(add),
+            remove: remove.iter().map(|name| name.to_string()).collect(),
+            remove_matching: remove_matching.iter().map(|re| re.to_string()).collect(),
+        }
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_apply() {
+        let server = rules(
+            &[
+                ("x-served-by", "$upstream_addr"),
+                ("x-request-id", "$request_id"),
+            ],
+            &[("via", "1.1 ${host}-proxy")],
+            &["server"],
+            &["^x-debug-"],
+        );
+        let location = rules(&[("x-served-by", "$scheme://$host $$5")], &[], &[], &[]);
+        let rewrite = HeaderRewrite::new(&[&server, &location]).unwrap();
+        let vars = HeaderVars {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            host: "acme.com",
+            tls: true,
+            request_id: "0af7651916cd43dd8448eb211c80319c",
+            upstream_addr: Some("10.0.0.1:3000".to_string()),
+        };
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.append_header("server", "backend").unwrap();
+        resp.append_header("x-debug-trace", "1").unwrap();
+        resp.append_header("x-debugger", "1").unwrap();
+        resp.append_header("via", "1.1 backend").unwrap();
+        rewrite.apply(&mut resp, &vars).unwrap();
+        assert!(resp.headers.get("server").is_none());
+        assert!(resp.headers.get("x-debug-trace").is_none());
+        assert_eq!(values(&resp.headers, "x-debugger"), ["1"]);
+        assert_eq!(
+            values(&resp.headers, "via"),
+            ["1.1 backend", "1.1 acme.com-proxy"]
+        );
+        assert_eq!(
+            values(&resp.headers, "x-served-by"),
+            ["https://acme.com $5"]
+        );
+        assert_eq!(
+            values(&resp.headers, "x-request-id"),
+            ["0af7651916cd43dd8448eb211c80319c"]
+        );
+
+        // values that expand to something invalid are skipped
+        let rewrite = HeaderRewrite::new(&[&rules(&[("x-host", "$host")], &[], &[], &[])]).unwrap();
+        let vars = HeaderVars {
+            host: "acme.com\r\nx-injected: 1",
+            ..vars
+        };
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        rewrite.apply(&mut req, &vars).unwrap();
+        assert!(req.headers.get("x-host").is_none());
+    }
+}
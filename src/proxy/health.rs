@@ -1,13 +1,33 @@
-use std::time::Duration;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
 #[cfg(unix)]
 use pingora::server::ListenFds;
 use pingora::{server::ShutdownWatch, services::Service};
+use pingora_core::connectors::http::Connector as HttpConnector;
+use pingora_error::{Error, ErrorType::Custom, ErrorType::CustomCode};
+use pingora_http::ResponseHeader;
+use pingora_load_balancing::{
+    Backend,
+    health_check::{self, HttpHealthCheck, TcpHealthCheck},
+};
+use tokio::{task::JoinHandle, time::Instant};
 use tracing::info;
 
-use crate::proxy::route::RouteTable;
+use crate::{
+    conf::{HealthCheckKind, HealthCheckResolved, ProxyProtocolVersion},
+    proxy::{
+        balancer::Balancer,
+        proxy_protocol::{ConnectOptions, ProxyProtocolConnector},
+        route::RouteTable,
+        tls::UpstreamTls,
+    },
+};
 
+// bodies are only scanned this far for the expected substring
+const MAX_HEALTH_CHECK_BODY: usize = 64 * 1024;
+
+// how often the scheduler looks for upstreams added by a config reload
 const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 pub struct HealthCheck {
     route_table: RouteTable,
@@ -24,19 +44,65 @@ impl Service for HealthCheck {
     async fn start_service(
         &mut self,
         #[cfg(unix)] _fds: Option<ListenFds>,
-        mut _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
     ) {
-        let mut ticker = tokio::time::interval(HEALTH_CHECK_INTERVAL);
-        let route = self.route_table.pin_owned();
+        // when each upstream is due next and its check in flight, tied to the load balancer it
+        // was scheduled for so that one replaced by a config reload is checked right away.
+        // Checks run as their own tasks so that a slow upstream does not hold back the others.
+        let mut schedule: HashMap<String, (Arc<Balancer>, Instant, Option<JoinHandle<()>>)> =
+            HashMap::new();
         loop {
-            ticker.tick().await;
-            for (host, entry) in route.iter() {
-                info!("health check: {}", host);
+            let now = Instant::now();
+            {
+                // pin on every round so that upstreams swapped in by a config reload are picked up
+                let upstreams = self.route_table.upstreams().pin();
+                schedule.retain(|name, (balancer, _, check)| {
+                    let current = upstreams
+                        .get(name)
+                        .is_some_and(|upstream| Arc::ptr_eq(&upstream.balancer, balancer));
+                    if !current && let Some(check) = check {
+                        check.abort();
+                    }
+                    current
+                });
+                for (name, upstream) in upstreams.iter() {
+                    let (balancer, next, check) = schedule
+                        .entry(name.clone())
+                        .or_insert_with(|| (upstream.balancer.clone(), now, None));
+                    if *next > now {
+                        continue;
+                    }
+                    *next = now + upstream.config.health_check.interval;
+                    // a check still running past its interval is not stacked with another one
+                    if check.as_ref().is_some_and(|check| !check.is_finished()) {
+                        continue;
+                    }
+                    let (name, balancer) = (name.clone(), balancer.clone());
+                    *check = Some(tokio::spawn(async move {
+                        info!("health check: {}", name);
+                        balancer.update().await.ok();
+                        balancer.run_health_check(true).await;
+                    }));
+                }
+            }
 
-                entry.upstream.update().await.ok();
-                entry.upstream.backends().run_health_check(true).await;
+            let idle = Instant::now() + HEALTH_CHECK_INTERVAL;
+            let next = schedule
+                .values()
+                .map(|(_, next, _)| *next)
+                .min()
+                .map_or(idle, |next| next.min(idle));
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+        for (_, _, check) in schedule.into_values() {
+            if let Some(check) = check {
+                check.abort();
             }
         }
+        info!("health check stopped");
     }
 
     fn name(&self) -> &str {
@@ -47,3 +113,124 @@ impl Service for HealthCheck {
         Some(1)
     }
 }
+
+/// Build the checker of an upstream from its `health_check` block. With `proxy_protocol`
+/// probes start with a header without addresses, as load balancers' own connections do.
+pub(crate) fn health_check(
+    config: &HealthCheckResolved,
+    tls: bool,
+    upstream_tls: Option<&UpstreamTls>,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+) -> Box<dyn health_check::HealthCheck + Send + Sync + 'static> {
+    let proxy_protocol = proxy_protocol.map(|version| {
+        let options = ConnectOptions {
+            timeout: Some(config.timeout),
+            ..Default::default()
+        };
+        ProxyProtocolConnector::new(version, None, options)
+    });
+    match &config.kind {
+        HealthCheckKind::Tcp => {
+            let mut hc = TcpHealthCheck::new();
+            hc.peer_template.options.connection_timeout = Some(config.timeout);
+            hc.peer_template.options.custom_l4 = proxy_protocol.map(|connector| {
+                Arc::new(connector) as Arc<dyn pingora_core::connectors::L4Connect + Send + Sync>
+            });
+            hc.consecutive_success = config.healthy_threshold;
+            hc.consecutive_failure = config.unhealthy_threshold;
+            hc
+        }
+        HealthCheckKind::Http(http) => {
+            let mut hc = HttpHealthCheck::new(&http.host, tls);
+            hc.req
+                .set_uri(http.path.parse().expect("path validated on load"));
+            hc.peer_template.options.connection_timeout = Some(config.timeout);
+            hc.peer_template.options.read_timeout = Some(config.timeout);
+            match upstream_tls {
+                Some(upstream_tls) => {
+                    upstream_tls.apply_health_check(&mut hc.peer_template, proxy_protocol)
+                }
+                None => {
+                    hc.peer_template.options.custom_l4 = proxy_protocol.map(|connector| {
+                        Arc::new(connector)
+                            as Arc<dyn pingora_core::connectors::L4Connect + Send + Sync>
+                    });
+                }
+            }
+            hc.consecutive_success = config.healthy_threshold;
+            hc.consecutive_failure = config.unhealthy_threshold;
+            let expected = http.expected_status.clone();
+            hc.validator = Some(Box::new(move |resp: &ResponseHeader| {
+                if expected.contains(&resp.status.as_u16()) {
+                    Ok(())
+                } else {
+                    Error::e_explain(
+                        CustomCode("unexpected status", resp.status.as_u16()),
+                        "during http healthcheck",
+                    )
+                }
+            }));
+            match &http.body {
+                Some(body) => Box::new(HttpBodyHealthCheck {
+                    http: hc,
+                    body: body.clone(),
+                    connector: HttpConnector::new(None),
+                }),
+                None => Box::new(hc),
+            }
+        }
+    }
+}
+
+/// [HttpHealthCheck] that also requires the response body to contain a substring, pingora's
+/// check only looks at the response header.
+struct HttpBodyHealthCheck {
+    http: HttpHealthCheck,
+    body: String,
+    connector: HttpConnector,
+}
+
+#[async_trait]
+impl health_check::HealthCheck for HttpBodyHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        self.http.health_threshold(success)
+    }
+
+    async fn check(&self, target: &Backend) -> pingora_error::Result<()> {
+        let mut peer = self.http.peer_template.clone();
+        peer._address = target.addr.clone();
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+
+        session
+            .write_request_header(Box::new(self.http.req.clone()))
+            .await?;
+        session.finish_request_body().await?;
+        if let Some(read_timeout) = peer.options.read_timeout {
+            session.set_read_timeout(read_timeout);
+        }
+        session.read_response_header().await?;
+        let resp = session.response_header().expect("just read");
+        if let Some(validator) = self.http.validator.as_ref() {
+            validator(resp)?;
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            if body.len() < MAX_HEALTH_CHECK_BODY {
+                body.extend_from_slice(&chunk);
+            }
+        }
+        let expected = self.body.as_bytes();
+        if body
+            .windows(expected.len())
+            .any(|window| window == expected)
+        {
+            Ok(())
+        } else {
+            Error::e_explain(
+                Custom("unexpected body"),
+                format!("health check body does not contain `{}`", self.body),
+            )
+        }
+    }
+}
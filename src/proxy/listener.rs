@@ -0,0 +1,903 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use papaya::HashMap;
+#[cfg(unix)]
+use pingora::server::ListenFds;
+use pingora::{
+    proxy::Session,
+    server::ShutdownWatch,
+    services::Service,
+    tls::{hash_certificate, load_certs_and_key_files},
+};
+use rustls::{
+    RootCertStore, ServerConfig, SupportedProtocolVersion,
+    crypto::{CryptoProvider, aws_lc_rs},
+    pki_types::CertificateDer,
+    server::{
+        ClientHello, NoServerSessionStorage, ResolvesServerCert, ServerSessionMemoryCache,
+        WebPkiClientVerifier,
+    },
+    sign::CertifiedKey,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixSocket},
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    conf::{
+        ClientAuth, ListenerAddr, ListenerConfigResolved, ProxyConfig, ProxyProtocolMode,
+        SimpleProxyConfigResolved, TlsConfigResolved, TlsProfile, TlsVersion,
+    },
+    proxy::{
+        matcher::HostMatcher,
+        proxy_protocol::{Rewind, read_header},
+        reload::CertReloader,
+    },
+};
+
+// a client that has not finished the handshake by then is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// what [TcpListener::bind] uses
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// A downstream connection accepted by a [RelayListener].
+#[derive(Debug)]
+pub struct Downstream {
+    /// the client, as told by the PROXY protocol header if the connection came with one
+    pub client_addr: SocketAddr,
+    /// what the client connected to
+    pub server_addr: SocketAddr,
+    /// `None` on listeners without tls
+    pub tls: Option<DownstreamTls>,
+}
+
+/// What the listener learnt about a downstream connection during the TLS handshake.
+#[derive(Debug)]
+pub struct DownstreamTls {
+    pub sni: Option<String>,
+    /// `None` if the client did not present a certificate
+    pub client_cert: Option<ClientIdentity>,
+    // the certificates the connection was accepted with
+    certs: Arc<Certificates>,
+}
+
+impl DownstreamTls {
+    /// Whether the certificate served on the connection is the one selected for `host`.
+    /// Clients reuse connections for any host the certificate is valid for, requests for a
+    /// server with a certificate of its own must come on a connection of their own.
+    pub fn serves(&self, host: &str) -> bool {
+        let Some(sni) = &self.sni else {
+            return true;
+        };
+        Arc::ptr_eq(self.certs.find(sni), self.certs.find(host))
+    }
+}
+
+/// The certificates of the listener: the one of `global.tls` and those of the servers with a
+/// `cert` of their own, selected by SNI like servers are by host.
+#[derive(Debug)]
+pub(crate) struct Certificates {
+    default: Arc<CertifiedKey>,
+    // every server name, `None` for the servers without a certificate
+    servers: std::collections::HashMap<String, Option<Arc<CertifiedKey>>>,
+    hosts: HostMatcher,
+}
+
+impl Certificates {
+    fn new(
+        config: &SimpleProxyConfigResolved,
+        tls: &TlsConfigResolved,
+        reject_expired: bool,
+    ) -> anyhow::Result<Self> {
+        // servers sharing files share the certificate, so they can share connections
+        let mut loaded = std::collections::HashMap::new();
+        let mut load = |cert: &str, key: &str| -> anyhow::Result<Arc<CertifiedKey>> {
+            if let Some(certified) = loaded.get(&(cert.to_string(), key.to_string())) {
+                return Ok(Arc::clone(certified));
+            }
+            let certified = Arc::new(load_certified_key(cert, key)?);
+            if let Err(e) = check_validity(cert, &certified) {
+                if reject_expired {
+                    return Err(e);
+                }
+                warn!("tls listener: {:#}", e);
+            }
+            loaded.insert((cert.to_string(), key.to_string()), certified.clone());
+            Ok(certified)
+        };
+        let default = load(&tls.cert, &tls.key)?;
+        let mut servers = std::collections::HashMap::new();
+        for (name, server) in config.servers.iter() {
+            let certified = match (&server.cert, &server.key) {
+                (Some(cert), Some(key)) => Some(load(cert, key)?),
+                _ => None,
+            };
+            servers.insert(name.clone(), certified);
+        }
+        Ok(Self {
+            default,
+            servers,
+            hosts: HostMatcher::new(config)?,
+        })
+    }
+
+    /// The certificate for a server name, the default one if no server has a certificate of
+    /// its own for it.
+    pub fn find(&self, name: &str) -> &Arc<CertifiedKey> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        self.servers
+            .get(&name)
+            .or_else(|| {
+                self.hosts
+                    .matches(&name)
+                    .find_map(|server| self.servers.get(server))
+            })
+            .and_then(Option::as_ref)
+            .unwrap_or(&self.default)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(match hello.server_name() {
+            Some(sni) => Arc::clone(self.find(sni)),
+            None => Arc::clone(&self.default),
+        })
+    }
+}
+
+/// What new handshakes are served with, replaced as a whole when the certificates are
+/// reloaded. Established connections keep the one they were accepted with.
+pub(crate) struct ListenerTls {
+    acceptor: TlsAcceptor,
+    // the same without h2 in ALPN, for the listeners with `http2: false`
+    http1_acceptor: TlsAcceptor,
+    certs: Arc<Certificates>,
+}
+
+impl ListenerTls {
+    /// Load the certificates, client CA and CRL of `config`. With `reject_expired` a
+    /// certificate outside of its validity period is an error rather than a warning.
+    pub fn new(config: &SimpleProxyConfigResolved, reject_expired: bool) -> anyhow::Result<Self> {
+        let tls = config
+            .global
+            .tls
+            .as_ref()
+            .context("the tls listener requires global.tls")?;
+        let certs = Arc::new(Certificates::new(config, tls, reject_expired)?);
+        let server_config = server_config(tls, certs.clone())?;
+        let mut http1 = server_config.clone();
+        http1.alpn_protocols.retain(|protocol| protocol != b"h2");
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            http1_acceptor: TlsAcceptor::from(Arc::new(http1)),
+            certs,
+        })
+    }
+
+    fn acceptor(&self, http2: bool) -> &TlsAcceptor {
+        if http2 {
+            &self.acceptor
+        } else {
+            &self.http1_acceptor
+        }
+    }
+
+    /// The files [ListenerTls::new] reads.
+    pub fn files(config: &SimpleProxyConfigResolved) -> BTreeSet<PathBuf> {
+        let global = config.global.tls.iter().flat_map(|tls| {
+            [
+                Some(&tls.cert),
+                Some(&tls.key),
+                tls.client_ca.as_ref(),
+                tls.crl.as_ref(),
+            ]
+        });
+        let servers = config
+            .servers
+            .values()
+            .flat_map(|server| [server.cert.as_ref(), server.key.as_ref()]);
+        global.chain(servers).flatten().map(PathBuf::from).collect()
+    }
+}
+
+/// A verified client certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// distinguished name, e.g. `CN=billing, O=Acme`
+    pub subject: String,
+    /// `DNS:`, `URI:`, `email:` or `IP:` prefixed
+    pub san: Vec<String>,
+    /// lowercase hex SHA-256 of the DER certificate
+    pub fingerprint: String,
+}
+
+/// Connections of the relay listeners keyed by the path of the socket they are relayed to the
+/// proxy from, which is what the proxy sees as the client address.
+#[derive(Clone, Default)]
+pub struct Downstreams(Arc<HashMap<PathBuf, Arc<Downstream>>>);
+
+impl Downstreams {
+    /// `None` if the session did not come through a relay listener.
+    pub fn get(&self, session: &Session) -> Option<Arc<Downstream>> {
+        let addr = session.client_addr()?.as_unix()?;
+        self.0.pin().get(addr.as_pathname()?).cloned()
+    }
+}
+
+/// What the relay listeners share: the certificates they are served with and the connections
+/// they relay.
+pub struct RelayListeners {
+    relay: Arc<Relay>,
+}
+
+/// Accepts the connections of a listener with `proxy_protocol`, or `tls` pingora's listener
+/// cannot serve, and relays each to the proxy over a Unix socket. pingora's rustls listener
+/// serves a single certificate without client certificates and its listeners do not read
+/// PROXY protocol headers, so the handshake and the header are taken care of here and the
+/// client's address and certificate are handed over in [Downstreams].
+pub struct RelayListener {
+    config: ListenerConfigResolved,
+    name: String,
+    // bound along with the other listeners, before the server starts
+    listener: Option<std::net::TcpListener>,
+    // where the proxy accepts the connections of this listener
+    proxy_path: PathBuf,
+    relay: Arc<Relay>,
+}
+
+struct Relay {
+    // only loaded if a listener has tls
+    tls: Option<Arc<ArcSwap<ListenerTls>>>,
+    downstreams: Downstreams,
+    // holds the proxy sockets and one socket per connection
+    dir: PathBuf,
+    next: AtomicU64,
+}
+
+impl RelayListeners {
+    pub fn new(
+        config: &SimpleProxyConfigResolved,
+        downstreams: Downstreams,
+    ) -> anyhow::Result<Self> {
+        let tls = if config.global.listeners.iter().any(|listener| listener.tls) {
+            let tls = ListenerTls::new(config, false)?;
+            Some(Arc::new(ArcSwap::from_pointee(tls)))
+        } else {
+            None
+        };
+        Ok(Self {
+            relay: Arc::new(Relay {
+                tls,
+                downstreams,
+                dir: create_private_dir()?,
+                next: AtomicU64::new(0),
+            }),
+        })
+    }
+
+    /// Whether the connections of `listener` go through a [RelayListener] rather than
+    /// straight to pingora. pingora's rustls listener serves the certificate of `global.tls`
+    /// with rustls' defaults, which are those of the `intermediate` profile, and does not
+    /// reload it.
+    pub fn relays(config: &SimpleProxyConfigResolved, listener: &ListenerConfigResolved) -> bool {
+        if listener.proxy_protocol != ProxyProtocolMode::None {
+            return true;
+        }
+        // servers can only ask for client certificates if global.tls does
+        listener.tls
+            && config.global.tls.as_ref().is_none_or(|tls| {
+                tls.client_auth != ClientAuth::None
+                    || tls.profile != TlsProfile::Intermediate
+                    || tls.alpn != ["h2", "http/1.1"]
+                    || tls.session_tickets
+                    || tls.session_cache_size != 256
+                    || config.servers.values().any(|server| server.cert.is_some())
+            })
+    }
+
+    /// The service accepting the connections of a listener, see [RelayListeners::relays].
+    /// The address is bound right away, so that a listener that cannot listen keeps the
+    /// server from starting.
+    pub fn listener(&self, config: &ListenerConfigResolved) -> anyhow::Result<RelayListener> {
+        let listener = bind(config).with_context(|| format!("failed to bind {}", config.addr))?;
+        let id = self.relay.next.fetch_add(1, Ordering::Relaxed);
+        Ok(RelayListener {
+            config: config.clone(),
+            name: format!("relay-listener {}", config.name),
+            listener: Some(listener),
+            proxy_path: self.relay.dir.join(format!("proxy-{}.sock", id)),
+            relay: self.relay.clone(),
+        })
+    }
+
+    /// Reloads the certificates of the listeners, see [CertReloader]. `None` if no listener
+    /// has tls.
+    pub fn cert_reloader(&self, config: ProxyConfig) -> Option<CertReloader> {
+        let tls = self.relay.tls.clone()?;
+        Some(CertReloader::new(config, tls))
+    }
+}
+
+impl RelayListener {
+    /// Where the proxy has to listen for the relayed connections.
+    pub fn proxy_path(&self) -> &Path {
+        &self.proxy_path
+    }
+}
+
+#[async_trait]
+impl Service for RelayListener {
+    async fn start_service(
+        &mut self,
+        #[cfg(unix)] _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let listener = self.listener.take().expect("relay listener started once");
+        let listener = TcpListener::from_std(listener).expect("relay listener in a runtime");
+        info!("{}: listening on {}", self.name, self.config.addr);
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer_addr)) => {
+                        let relay = self.relay.clone();
+                        let (config, proxy_path) = (self.config.clone(), self.proxy_path.clone());
+                        let name = self.name.clone();
+                        tokio::spawn(async move {
+                            let handled = relay.handle(stream, peer_addr, &config, &proxy_path);
+                            if let Err(e) = handled.await {
+                                debug!("{}: {}: {:#}", name, peer_addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => warn!("{}: failed to accept: {}", self.name, e),
+                },
+                _ = shutdown.changed() => break,
+            }
+        }
+        info!("{} stopped", self.name);
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+// a fresh directory only this user can enter, like mkdtemp(3). The name is not predictable
+// and creating it fails if the path exists, so nobody else can have planted it or its files
+fn create_private_dir() -> anyhow::Result<PathBuf> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    let dir = std::env::temp_dir().join(format!(
+        "simple-proxy-{}-{:016x}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+// like [TcpListener::bind], with IPV6_V6ONLY as configured. There is no runtime yet, the
+// service makes a tokio listener of it when it starts
+fn bind(config: &ListenerConfigResolved) -> std::io::Result<std::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let ListenerAddr::Inet(addr) = config.addr else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "relay listeners do not support unix sockets",
+        ));
+    };
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(config.ipv6_only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// Remove the socket file a previous run left behind at the path of a unix listener. Binding
+/// would otherwise fail, pingora unlinks whatever is at the path, so files that are not
+/// sockets and sockets another process still accepts on are refused here instead.
+pub fn remove_stale_socket(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::{fs::FileTypeExt, net::UnixStream};
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("failed to stat {}", path.display())),
+    };
+    anyhow::ensure!(
+        metadata.file_type().is_socket(),
+        "{} exists and is not a socket",
+        path.display()
+    );
+    anyhow::ensure!(
+        UnixStream::connect(path).is_err(),
+        "{} is in use by another process",
+        path.display()
+    );
+    std::fs::remove_file(path)
+        .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    info!("removed stale socket {}", path.display());
+    Ok(())
+}
+
+// the listeners and their connections are gone, so are the sockets in the directory
+impl Drop for Relay {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.dir) {
+            warn!("failed to remove {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+impl Relay {
+    async fn handle(
+        &self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        config: &ListenerConfigResolved,
+        proxy_path: &Path,
+    ) -> anyhow::Result<()> {
+        let server_addr = stream.local_addr()?;
+        let (header, rest) = match config.proxy_protocol {
+            ProxyProtocolMode::None => (None, Bytes::new()),
+            mode => {
+                let read = read_header(&mut stream, mode == ProxyProtocolMode::Required);
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, read)
+                    .await
+                    .context("PROXY protocol header timed out")??
+            }
+        };
+        // a LOCAL or UNKNOWN header, or none, leaves the addresses of the connection itself
+        let (client_addr, server_addr) = header.map_or((peer_addr, server_addr), |header| {
+            (header.source, header.destination)
+        });
+        let stream = Rewind::new(rest, stream);
+
+        if !config.tls {
+            let downstream = Downstream {
+                client_addr,
+                server_addr,
+                tls: None,
+            };
+            return self.relay(stream, downstream, proxy_path).await;
+        }
+        let tls = self.tls.as_ref().context("listener tls is not loaded")?;
+        let listener_tls = tls.load_full();
+        let accept = listener_tls.acceptor(config.http2).accept(stream);
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept)
+            .await
+            .context("handshake timed out")?
+            .context("handshake failed")?;
+        let (_, conn) = stream.get_ref();
+        let downstream = Downstream {
+            client_addr,
+            server_addr,
+            tls: Some(DownstreamTls {
+                sni: conn.server_name().map(str::to_string),
+                client_cert: conn
+                    .peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .and_then(ClientIdentity::from_der),
+                certs: listener_tls.certs.clone(),
+            }),
+        };
+        self.relay(stream, downstream, proxy_path).await
+    }
+
+    async fn relay<S>(
+        &self,
+        mut stream: S,
+        downstream: Downstream,
+        proxy_path: &Path,
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // the proxy sees the path the socket is bound to as the client address, register
+        // it before connecting
+        let path = self.dir.join(format!(
+            "conn-{}.sock",
+            self.next.fetch_add(1, Ordering::Relaxed)
+        ));
+        let socket = UnixSocket::new_stream()?;
+        socket.bind(&path)?;
+        let _registration = Registration::new(&self.downstreams, path, downstream);
+        let mut proxy = socket
+            .connect(proxy_path)
+            .await
+            .context("failed to connect to the proxy")?;
+        tokio::io::copy_bidirectional(&mut stream, &mut proxy).await?;
+        Ok(())
+    }
+}
+
+// removes a connection from [Downstreams] and its socket file once it is closed
+struct Registration<'a> {
+    downstreams: &'a Downstreams,
+    path: PathBuf,
+}
+
+impl<'a> Registration<'a> {
+    fn new(downstreams: &'a Downstreams, path: PathBuf, downstream: Downstream) -> Self {
+        downstreams
+            .0
+            .pin()
+            .insert(path.clone(), Arc::new(downstream));
+        Self { downstreams, path }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.downstreams.0.pin().remove(&self.path);
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+impl ClientIdentity {
+    fn from_der(cert: &CertificateDer) -> Option<Self> {
+        let (_, x509) = X509Certificate::from_der(cert).ok()?;
+        let san = x509
+            .subject_alternative_name()
+            .ok()
+            .flatten()
+            .map(|ext| {
+                ext.value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        GeneralName::DNSName(dns) => Some(format!("DNS:{}", dns)),
+                        GeneralName::URI(uri) => Some(format!("URI:{}", uri)),
+                        GeneralName::RFC822Name(email) => Some(format!("email:{}", email)),
+                        GeneralName::IPAddress(ip) => {
+                            let ip: IpAddr = match *ip {
+                                [a, b, c, d] => Ipv4Addr::new(*a, *b, *c, *d).into(),
+                                _ => Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?).into(),
+                            };
+                            Some(format!("IP:{}", ip))
+                        }
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(Self {
+            subject: x509.subject().to_string(),
+            san,
+            fingerprint: hash_certificate(cert)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        })
+    }
+}
+
+fn load_certified_key(cert: &str, key: &str) -> anyhow::Result<CertifiedKey> {
+    let (certs, key_der) = load_certs_and_key_files(cert, key)
+        .with_context(|| format!("failed to load certificate {}", cert))?
+        .with_context(|| format!("no certificate or key in {} and {}", cert, key))?;
+    CertifiedKey::from_der(certs, key_der, &aws_lc_rs::default_provider())
+        .with_context(|| format!("invalid certificate or key {} and {}", cert, key))
+}
+
+// a certificate is checked for expiry on load only, the listener keeps serving it after
+fn check_validity(path: &str, certified: &CertifiedKey) -> anyhow::Result<()> {
+    let cert = certified.end_entity_cert()?;
+    let (_, x509) = X509Certificate::from_der(cert)
+        .map_err(|e| anyhow::anyhow!("invalid certificate {}: {}", path, e))?;
+    let validity = x509.validity();
+    if !validity.is_valid() {
+        anyhow::bail!(
+            "certificate {} is only valid from {} to {}",
+            path,
+            validity.not_before,
+            validity.not_after
+        );
+    }
+    Ok(())
+}
+
+fn server_config(
+    config: &TlsConfigResolved,
+    certs: Arc<Certificates>,
+) -> anyhow::Result<ServerConfig> {
+    // the provider offers the configured cipher suites only
+    let provider = Arc::new(CryptoProvider {
+        cipher_suites: aws_lc_rs::ALL_CIPHER_SUITES
+            .iter()
+            .filter(|suite| {
+                suite
+                    .suite()
+                    .as_str()
+                    .is_some_and(|name| config.ciphers.iter().any(|cipher| cipher == name))
+            })
+            .copied()
+            .collect(),
+        ..aws_lc_rs::default_provider()
+    });
+    let versions: Vec<&'static SupportedProtocolVersion> = config
+        .versions
+        .iter()
+        .map(|version| match version {
+            TlsVersion::Tls12 => &rustls::version::TLS12,
+            TlsVersion::Tls13 => &rustls::version::TLS13,
+        })
+        .collect();
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .context("invalid tls versions or cipher suites")?;
+    let builder = match (config.client_auth, &config.client_ca) {
+        (ClientAuth::None, _) | (_, None) => builder.with_no_client_auth(),
+        (client_auth, Some(client_ca)) => {
+            let mut roots = RootCertStore::empty();
+            let pem = std::fs::read(client_ca)
+                .with_context(|| format!("failed to read client ca {}", client_ca))?;
+            for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                roots
+                    .add(cert?)
+                    .with_context(|| format!("invalid certificate in {}", client_ca))?;
+            }
+            let mut verifier =
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            if let Some(crl) = &config.crl {
+                let pem =
+                    std::fs::read(crl).with_context(|| format!("failed to read crl {}", crl))?;
+                let crls = rustls_pemfile::crls(&mut pem.as_slice())
+                    .collect::<Result<Vec<_>, _>>()
+                    .with_context(|| format!("invalid crl {}", crl))?;
+                verifier = verifier
+                    .with_crls(crls)
+                    .only_check_end_entity_revocation()
+                    .allow_unknown_revocation_status();
+            }
+            if client_auth == ClientAuth::Optional {
+                verifier = verifier.allow_unauthenticated();
+            }
+            builder.with_client_cert_verifier(verifier.build()?)
+        }
+    };
+    let mut server_config = builder.with_cert_resolver(certs);
+    server_config.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    if config.session_tickets {
+        server_config.ticketer = aws_lc_rs::Ticketer::new()?;
+    }
+    server_config.session_storage = if config.session_cache_size == 0 {
+        Arc::new(NoServerSessionStorage {})
+    } else {
+        ServerSessionMemoryCache::new(config.session_cache_size)
+    };
+    Ok(server_config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_identity() {
+        let pem = std::fs::read("./fixtures/certs/proxy.crt").unwrap();
+        let cert = rustls_pemfile::certs(&mut pem.as_slice())
+            .next()
+            .unwrap()
+            .unwrap();
+        let identity = ClientIdentity::from_der(&cert).unwrap();
+        assert!(!identity.subject.is_empty());
+        assert!(identity.san.iter().all(|san| san.contains(':')));
+        assert_eq!(identity.fingerprint.len(), 64);
+    }
+
+    #[test]
+    fn test_certificates_by_sni() {
+        let yaml = r#"
+global:
+  port: 8443
+  tls:
+    cert: ./fixtures/certs/proxy.crt
+    key: ./fixtures/certs/proxy.key
+servers:
+  - server_name: [acme.com, www.acme.com]
+    upstream: backend
+  - server_name: ["*.api.acme.com"]
+    upstream: backend
+    cert: ./fixtures/certs/backend.crt
+    key: ./fixtures/certs/backend.key
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfigResolved = yaml
+            .parse::<crate::conf::SimpleProxyConfig>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        // the fixtures may have expired
+        let certs = Certificates::new(&config, config.global.tls.as_ref().unwrap(), false).unwrap();
+        let (default, api) = (certs.find("acme.com"), certs.find("v1.api.acme.com"));
+        assert!(!Arc::ptr_eq(default, api));
+        assert!(Arc::ptr_eq(certs.find("WWW.acme.com."), default));
+        assert!(Arc::ptr_eq(certs.find("unknown.com"), default));
+        assert!(Arc::ptr_eq(certs.find("v2.api.acme.com"), api));
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("simple-proxy-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy.sock");
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        // the file outlives the listener
+        drop(listener);
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        remove_stale_socket(&path).unwrap();
+
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relay_dir() {
+        let yaml = r#"
+global:
+  listeners:
+    - name: public
+      port: 8080
+      proxy_protocol: optional
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfigResolved = yaml
+            .parse::<crate::conf::SimpleProxyConfig>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let relays = RelayListeners::new(&config, Downstreams::default()).unwrap();
+        let other = RelayListeners::new(&config, Downstreams::default()).unwrap();
+        let dir = relays.relay.dir.clone();
+        assert_ne!(dir, other.relay.dir);
+
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // removed along with the last listener sharing it
+        let mut listener_config = config.global.listeners[0].clone();
+        listener_config.addr = ListenerAddr::Inet("127.0.0.1:0".parse().unwrap());
+        let listener = relays.listener(&listener_config).unwrap();
+        drop(relays);
+        assert!(dir.exists());
+        drop(listener);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_relay_listener_bind() {
+        let yaml = r#"
+global:
+  listeners:
+    - name: public
+      address: 127.0.0.1
+      port: 8080
+      proxy_protocol: optional
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#;
+        let config: SimpleProxyConfigResolved = yaml
+            .parse::<crate::conf::SimpleProxyConfig>()
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let relays = RelayListeners::new(&config, Downstreams::default()).unwrap();
+
+        // the address is taken when the listener is created, not once the server runs
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut listener_config = config.global.listeners[0].clone();
+        listener_config.addr = ListenerAddr::Inet(taken.local_addr().unwrap());
+        let e = relays.listener(&listener_config).err().unwrap();
+        assert!(e.to_string().starts_with("failed to bind"));
+
+        drop(taken);
+        relays.listener(&listener_config).unwrap();
+    }
+
+    #[test]
+    fn test_relays() {
+        let config = |tls: &str, servers: &str| -> SimpleProxyConfigResolved {
+            let yaml = format!(
+                r#"
+global:
+  tls:
+    cert: fixtures/certs/proxy.crt
+    key: fixtures/certs/proxy.key
+{tls}
+  listeners:
+    - name: https
+      port: 8443
+      tls: true
+    - name: http
+      port: 8080
+    - name: lb
+      port: 8081
+      proxy_protocol: required
+servers:
+  - server_name: [acme.com]
+    upstream: backend
+{servers}
+upstreams:
+  - name: backend
+    servers: ["127.0.0.1:3001"]
+"#
+            );
+            yaml.parse::<crate::conf::SimpleProxyConfig>()
+                .unwrap()
+                .try_into()
+                .unwrap()
+        };
+        let relays = |config: &SimpleProxyConfigResolved| -> Vec<bool> {
+            config
+                .global
+                .listeners
+                .iter()
+                .map(|listener| RelayListeners::relays(config, listener))
+                .collect()
+        };
+
+        // pingora's listener serves plain tls
+        assert_eq!(relays(&config("", "")), [false, false, true]);
+        let mtls = config(
+            "    client_auth: optional\n    client_ca: fixtures/certs/ca.crt",
+            "",
+        );
+        assert_eq!(relays(&mtls), [true, false, true]);
+        let modern = config("    profile: modern", "");
+        assert_eq!(relays(&modern), [true, false, true]);
+        let sni = config(
+            "",
+            "  - server_name: [backend.acme.com]\n    upstream: backend\n    cert: fixtures/certs/backend.crt\n    key: fixtures/certs/backend.key",
+        );
+        assert_eq!(relays(&sni), [true, false, true]);
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::http::{HeaderName, Method};
+use pingora::http::RequestHeader;
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    conf::{LocationPath, MatchConfigResolved, ServerName, SimpleProxyConfigResolved, ValueMatch},
+    proxy::utils::get_cookies,
+};
+
+/// Resolves a request host to the `server_name` it is routed by. Exact names are looked up in
+/// the route table directly, this handles the wildcards, regexes and the default server.
+#[derive(Debug, Default)]
+pub(crate) struct HostMatcher {
+    // `.acme.com` -> `*.acme.com`
+    suffixes: HashMap<String, String>,
+    // `www.acme.` -> `www.acme.*`
+    prefixes: HashMap<String, String>,
+    regexes: Vec<(Regex, String)>,
+    default: Option<String>,
+}
+
+impl HostMatcher {
+    pub fn new(config: &SimpleProxyConfigResolved) -> anyhow::Result<Self> {
+        let mut matcher = Self {
+            default: config.default_server.clone(),
+            ..Default::default()
+        };
+        for name in config.server_names.iter() {
+            match name.parse::<ServerName>().map_err(anyhow::Error::msg)? {
+                ServerName::Exact(_) => {}
+                ServerName::Suffix(suffix) => {
+                    matcher.suffixes.insert(suffix, name.clone());
+                }
+                ServerName::Prefix(prefix) => {
+                    matcher.prefixes.insert(prefix, name.clone());
+                }
+                // hosts are lowercased before they are matched
+                ServerName::Regex(pattern) => {
+                    let regex = RegexBuilder::new(&pattern).case_insensitive(true).build()?;
+                    matcher.regexes.push((regex, name.clone()));
+                }
+            }
+        }
+        Ok(matcher)
+    }
+
+    /// Candidate names for `host` in precedence order, the default server last. `host` must
+    /// already be lowercased.
+    pub fn find<'a>(&'a self, host: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.matches(host).chain(self.default.as_deref())
+    }
+
+    /// Like [HostMatcher::find] without the default server.
+    pub fn matches<'a>(&'a self, host: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        // walk the labels from the left so that the longest suffix is tried first
+        let suffixes = host
+            .match_indices('.')
+            .filter_map(|(i, _)| self.suffixes.get(&host[i..]));
+        // and from the right for the longest prefix
+        let prefixes = host
+            .rmatch_indices('.')
+            .filter_map(|(i, _)| self.prefixes.get(&host[..=i]));
+        let regexes = self
+            .regexes
+            .iter()
+            .filter(|(regex, _)| regex.is_match(host))
+            .map(|(_, name)| name);
+
+        suffixes
+            .chain(prefixes)
+            .chain(regexes)
+            .map(|name| name.as_str())
+    }
+}
+
+/// Matches a request path against the locations of a server: exact paths, then prefixes from
+/// the longest, then regexes in insertion order. Prefixes are kept in a map and
+/// probed from the longest configured length down, so a lookup costs one hash per distinct
+/// prefix length instead of a scan over all locations. A path may hold several values (e.g.
+/// with different match blocks), they are returned in insertion order.
+#[derive(Debug)]
+pub(crate) struct PathMatcher<T> {
+    exact: HashMap<String, Vec<T>>,
+    regexes: Vec<(Regex, T)>,
+    prefixes: HashMap<String, Vec<T>>,
+    // distinct prefix lengths, longest first
+    prefix_lens: Vec<usize>,
+}
+
+impl<T> PathMatcher<T> {
+    pub fn insert(&mut self, path: &LocationPath, value: T) -> anyhow::Result<()> {
+        match path {
+            LocationPath::Exact(path) => self.exact.entry(path.clone()).or_default().push(value),
+            LocationPath::Regex(pattern) => self.regexes.push((Regex::new(pattern)?, value)),
+            LocationPath::Prefix(prefix) => {
+                if let Err(i) = self
+                    .prefix_lens
+                    .binary_search_by(|len| prefix.len().cmp(len))
+                {
+                    self.prefix_lens.insert(i, prefix.len());
+                }
+                self.prefixes.entry(prefix.clone()).or_default().push(value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Values whose path matches, in precedence order.
+    pub fn find<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a T> + 'a {
+        let exact = self.exact.get(path).into_iter().flatten();
+        let prefixes = self
+            .prefix_lens
+            .iter()
+            .filter_map(|&len| path.get(..len))
+            .filter_map(|prefix| self.prefixes.get(prefix))
+            .flatten();
+        let regexes = self
+            .regexes
+            .iter()
+            .filter(|(regex, _)| regex.is_match(path))
+            .map(|(_, value)| value);
+        exact.chain(prefixes).chain(regexes)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.exact
+            .values()
+            .flatten()
+            .chain(self.regexes.iter().map(|(_, value)| value))
+            .chain(self.prefixes.values().flatten())
+    }
+}
+
+impl<T> Default for PathMatcher<T> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            regexes: Vec::new(),
+            prefixes: HashMap::new(),
+            prefix_lens: Vec::new(),
+        }
+    }
+}
+
+/// Compiled `match` block of a server or location.
+#[derive(Debug)]
+pub(crate) struct RequestMatcher {
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, ValuePattern)>,
+    query: Vec<(String, ValuePattern)>,
+    cookies: Vec<(String, ValuePattern)>,
+}
+
+#[derive(Debug)]
+enum ValuePattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl RequestMatcher {
+    pub fn new(config: &MatchConfigResolved) -> anyhow::Result<Self> {
+        let methods = config
+            .methods
+            .iter()
+            .map(|method| Method::from_bytes(method.as_bytes()))
+            .collect::<Result<_, _>>()?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes())?;
+                Ok((name, ValuePattern::try_from(value)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let query = compile(&config.query)?;
+        let cookies = compile(&config.cookies)?;
+        Ok(Self {
+            methods,
+            headers,
+            query,
+            cookies,
+        })
+    }
+
+    pub fn matches(&self, req: &RequestHeader) -> bool {
+        if !self.methods.is_empty() && !self.methods.contains(&req.method) {
+            return false;
+        }
+
+        let headers = self.headers.iter().all(|(name, pattern)| {
+            req.headers
+                .get_all(name)
+                .iter()
+                .any(|value| value.to_str().is_ok_and(|value| pattern.matches(value)))
+        });
+        if !headers {
+            return false;
+        }
+
+        // names and values are percent-decoded, `+` being a space as in forms
+        let query = || form_urlencoded::parse(req.uri.query().unwrap_or_default().as_bytes());
+        if !self.query.iter().all(|(name, pattern)| {
+            query().any(|(key, value)| key == name && pattern.matches(value))
+        }) {
+            return false;
+        }
+
+        self.cookies.iter().all(|(name, pattern)| {
+            get_cookies(req).any(|(key, value)| key == name && pattern.matches(value))
+        })
+    }
+}
+
+impl ValuePattern {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Self::Exact(expected) => expected == value,
+            Self::Regex(regex) => regex.is_match(value),
+        }
+    }
+}
+
+impl TryFrom<&ValueMatch> for ValuePattern {
+    type Error = regex::Error;
+
+    fn try_from(value: &ValueMatch) -> Result<Self, Self::Error> {
+        match value {
+            ValueMatch::Exact(value) => Ok(Self::Exact(value.clone())),
+            ValueMatch::Regex(pattern) => Ok(Self::Regex(Regex::new(pattern)?)),
+        }
+    }
+}
+
+fn compile(values: &BTreeMap<String, ValueMatch>) -> anyhow::Result<Vec<(String, ValuePattern)>> {
+    values
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), ValuePattern::try_from(value)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::{MatchConfig, SimpleProxyConfig};
+
+    fn matcher(server_names: &str) -> HostMatcher {
+        let yaml = format!(
+            r#"
+global:
+  port: 3000
+servers:
+  - server_name: {}
+    upstream: web_servers
+  - server_name: [fallback]
+    upstream: web_servers
+    default_server: true
+upstreams:
+  - name: web_servers
+    servers: ["127.0.0.1:3001"]
+"#,
+            server_names
+        );
+        let config: SimpleProxyConfig = yaml.parse().unwrap();
+        let config = SimpleProxyConfigResolved::try_from(config).unwrap();
+        HostMatcher::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_host_matcher_precedence() {
+        let matcher =
+            matcher(r#"["*.acme.com", "*.api.acme.com", "www.acme.*", "~^api\\d+\\.", "~^api"]"#);
+
+        assert_eq!(matcher.find("www.acme.com").next(), Some("*.acme.com"));
+        assert_eq!(
+            matcher.find("v1.api.acme.com").next(),
+            Some("*.api.acme.com")
+        );
+        assert_eq!(matcher.find("www.acme.org").next(), Some("www.acme.*"));
+        assert_eq!(
+            matcher.find("api1.example.com").next(),
+            Some("~^api\\d+\\.")
+        );
+        assert_eq!(matcher.find("apix.example.com").next(), Some("~^api"));
+        let upper = matcher(r#"["~^API\\d+\\.acme\\.com$"]"#);
+        assert_eq!(
+            upper.find("api1.acme.com").next(),
+            Some("~^API\\d+\\.acme\\.com$")
+        );
+        assert_eq!(matcher.find("example.com").next(), Some("fallback"));
+        // a leading wildcard does not match the bare domain
+        assert_eq!(matcher.find("acme.com").next(), Some("fallback"));
+        // less specific names follow, for servers whose match block does not hold
+        assert_eq!(
+            matcher.find("www.acme.com").collect::<Vec<_>>(),
+            vec!["*.acme.com", "www.acme.*", "fallback"]
+        );
+    }
+
+    #[test]
+    fn test_path_matcher_precedence() -> anyhow::Result<()> {
+        let mut matcher = PathMatcher::default();
+        for path in ["/", "/v1/", "/v1/users", "= /v1", "~ \\.json$"] {
+            matcher.insert(&path.parse().map_err(anyhow::Error::msg)?, path)?;
+        }
+
+        assert_eq!(matcher.find("/v1").next(), Some(&"= /v1"));
+        assert_eq!(matcher.find("/v1/users/1").next(), Some(&"/v1/users"));
+        assert_eq!(matcher.find("/v1/orders").next(), Some(&"/v1/"));
+        // the longest prefix wins over a regex, which only follows the prefixes
+        assert_eq!(
+            matcher.find("/v1/users.json").collect::<Vec<_>>(),
+            [&"/v1/users", &"/v1/", &"/", &"~ \\.json$"]
+        );
+        assert_eq!(matcher.find("/static/app.js").next(), Some(&"/"));
+
+        let mut matcher = PathMatcher::default();
+        for path in ["/v1/", "~ \\.json$"] {
+            matcher.insert(&path.parse().map_err(anyhow::Error::msg)?, path)?;
+        }
+        assert_eq!(matcher.find("/static/app.json").next(), Some(&"~ \\.json$"));
+        assert_eq!(matcher.find("/v1/app.json").next(), Some(&"/v1/"));
+
+        let matcher = PathMatcher::<&str>::default();
+        assert_eq!(matcher.find("/").next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_matcher() -> anyhow::Result<()> {
+        let config: MatchConfig = serde_yaml::from_str(
+            r#"
+method: [post, PUT]
+headers:
+  X-Tenant: "~^(acme|globex)$"
+query:
+  version: "2"
+  tenant: "a b"
+cookies:
+  beta: "1"
+"#,
+        )?;
+        let config = MatchConfigResolved::try_from_with_location(&config, "match")?;
+        let matcher = RequestMatcher::new(&config)?;
+
+        let request = |method: &str, uri: &str, tenant: &str| -> anyhow::Result<_> {
+            let mut req = RequestHeader::build(method, uri.as_bytes(), None)?;
+            req.insert_header("x-tenant", tenant)?;
+            req.insert_header("cookie", "session=abc; beta=1")?;
+            Ok(req)
+        };
+        assert!(matcher.matches(&request(
+            "POST",
+            "/users?a=b&version=2&tenant=a%20b",
+            "acme"
+        )?));
+        assert!(matcher.matches(&request("PUT", "/users?tenant=a+b&version=2", "globex")?));
+        assert!(!matcher.matches(&request("GET", "/users?tenant=a%20b&version=2", "acme")?));
+        assert!(!matcher.matches(&request("POST", "/users?tenant=a%20b&version=1", "acme")?));
+        assert!(!matcher.matches(&request(
+            "POST",
+            "/users?tenant=a%20b&version=2",
+            "initech"
+        )?));
+        assert!(!matcher.matches(&request("POST", "/users?version=2&tenant=a%2520b", "acme")?));
+        assert!(matcher.matches(&request(
+            "POST",
+            "/users?versio%6E=%32&tenant=a%20b",
+            "acme"
+        )?));
+        Ok(())
+    }
+}
@@ -1,7 +1,24 @@
+mod balancer;
+mod forwarded;
+mod headers;
 mod health;
+mod listener;
+mod matcher;
+mod outlier;
+mod proxy_protocol;
+mod reload;
+mod retry;
 mod route;
 mod simple_proxy;
+mod tls;
 pub(crate) mod utils;
 
+pub use balancer::*;
 pub use health::*;
+pub use listener::*;
+pub use outlier::*;
+pub use proxy_protocol::*;
+pub use reload::*;
+pub use route::*;
 pub use simple_proxy::*;
+pub use tls::*;
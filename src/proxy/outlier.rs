@@ -0,0 +1,251 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use papaya::HashMap;
+use pingora_load_balancing::Backend;
+use tracing::{info, warn};
+
+use crate::conf::OutlierDetectionResolved;
+
+/// How a request to a backend went, as seen by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    /// the backend answered with a 5xx
+    ServerError,
+    ConnectFailure,
+    /// the connection broke while proxying
+    Error,
+}
+
+/// Ejects backends of an upstream that fail live traffic, see `outlier_detection` in the
+/// upstream config. Ejected backends are skipped by selection until their ejection expires.
+pub(crate) struct OutlierDetector {
+    upstream: String,
+    config: OutlierDetectionResolved,
+    backends: HashMap<Backend, Mutex<Stats>>,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    consecutive_5xx: u32,
+    consecutive_connect_failures: u32,
+    window_start: Option<Instant>,
+    requests: u32,
+    errors: u32,
+    // ejections in a row, the ejection time doubles with each
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    ejected: bool,
+}
+
+impl OutlierDetector {
+    pub fn new(upstream: &str, config: &OutlierDetectionResolved) -> Self {
+        Self {
+            upstream: upstream.to_string(),
+            config: config.clone(),
+            backends: HashMap::new(),
+        }
+    }
+
+    pub fn is_ejected(&self, backend: &Backend) -> bool {
+        let backends = self.backends.pin();
+        let Some(stats) = backends.get(backend) else {
+            return false;
+        };
+        let mut stats = stats.lock().expect("outlier stats poisoned");
+        if !stats.ejected {
+            return false;
+        }
+        if stats
+            .ejected_until
+            .is_some_and(|until| until > Instant::now())
+        {
+            return true;
+        }
+        stats.ejected = false;
+        info!(
+            "outlier detection: {} re-admitted to upstream {}",
+            backend.addr, self.upstream
+        );
+        false
+    }
+
+    /// Record the outcome of a request, `total` is the number of backends of the upstream.
+    pub fn report(&self, backend: &Backend, outcome: Outcome, total: usize) {
+        let now = Instant::now();
+        let backends = self.backends.pin();
+        let stats = backends.get_or_insert_with(backend.clone(), Default::default);
+        let Some(reason) =
+            stats
+                .lock()
+                .expect("outlier stats poisoned")
+                .record(outcome, &self.config, now)
+        else {
+            return;
+        };
+
+        let ejected = backends
+            .values()
+            .filter(|stats| {
+                let stats = stats.lock().expect("outlier stats poisoned");
+                stats.ejected && stats.ejected_until.is_some_and(|until| until > now)
+            })
+            .count();
+        if (ejected + 1) * 100 > self.config.max_ejection_percent as usize * total {
+            warn!(
+                "outlier detection: not ejecting {} from upstream {} ({}), {} of {} servers already ejected",
+                backend.addr, self.upstream, reason, ejected, total
+            );
+            stats.lock().expect("outlier stats poisoned").reset();
+            return;
+        }
+
+        let duration = stats
+            .lock()
+            .expect("outlier stats poisoned")
+            .eject(&self.config, now);
+        warn!(
+            "outlier detection: ejecting {} from upstream {} for {:?} ({})",
+            backend.addr, self.upstream, duration, reason
+        );
+    }
+}
+
+impl Stats {
+    /// Returns why the backend should be ejected, if it should.
+    fn record(
+        &mut self,
+        outcome: Outcome,
+        config: &OutlierDetectionResolved,
+        now: Instant,
+    ) -> Option<&'static str> {
+        if self.ejected {
+            return None;
+        }
+        match outcome {
+            Outcome::Success => {
+                self.consecutive_5xx = 0;
+                self.consecutive_connect_failures = 0;
+            }
+            Outcome::ServerError => {
+                self.consecutive_5xx += 1;
+                self.consecutive_connect_failures = 0;
+            }
+            Outcome::ConnectFailure => self.consecutive_connect_failures += 1,
+            Outcome::Error => {}
+        }
+
+        if self
+            .window_start
+            .is_none_or(|start| now.duration_since(start) >= config.window)
+        {
+            self.window_start = Some(now);
+            self.requests = 0;
+            self.errors = 0;
+        }
+        self.requests += 1;
+        if outcome != Outcome::Success {
+            self.errors += 1;
+        }
+
+        if config.consecutive_5xx > 0 && self.consecutive_5xx >= config.consecutive_5xx {
+            Some("consecutive 5xx")
+        } else if config.consecutive_connect_failures > 0
+            && self.consecutive_connect_failures >= config.consecutive_connect_failures
+        {
+            Some("consecutive connect failures")
+        } else if let Some(rate) = config.error_rate
+            && self.requests >= config.min_requests.max(1)
+            && self.errors as f64 / self.requests as f64 >= rate
+        {
+            Some("error rate")
+        } else {
+            None
+        }
+    }
+
+    fn eject(&mut self, config: &OutlierDetectionResolved, now: Instant) -> Duration {
+        // a backend that behaved for a while starts over at the base ejection time
+        if self
+            .ejected_until
+            .is_some_and(|until| now > until + config.max_ejection_time)
+        {
+            self.ejections = 0;
+        }
+        let factor = 2u32.saturating_pow(self.ejections);
+        let duration = config
+            .base_ejection_time
+            .saturating_mul(factor)
+            .min(config.max_ejection_time);
+        self.ejections = self.ejections.saturating_add(1);
+        self.ejected = true;
+        self.ejected_until = Some(now + duration);
+        self.reset();
+        duration
+    }
+
+    fn reset(&mut self) {
+        self.consecutive_5xx = 0;
+        self.consecutive_connect_failures = 0;
+        self.window_start = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::{OutlierDetectionConfig, OutlierDetectionResolved};
+
+    fn detector(yaml: &str) -> OutlierDetector {
+        let config: OutlierDetectionConfig = serde_yaml::from_str(yaml).unwrap();
+        let config = OutlierDetectionResolved::try_from_with_location(&config, "")
+            .unwrap()
+            .unwrap();
+        OutlierDetector::new("web_servers", &config)
+    }
+
+    #[test]
+    fn test_consecutive_failures_eject() {
+        let detector = detector("{consecutive_5xx: 3, max_ejection_percent: 100}");
+        let backend = Backend::new("127.0.0.1:3001").unwrap();
+
+        for outcome in [Outcome::ServerError, Outcome::ServerError, Outcome::Success] {
+            detector.report(&backend, outcome, 2);
+        }
+        assert!(!detector.is_ejected(&backend));
+        for _ in 0..3 {
+            detector.report(&backend, Outcome::ServerError, 2);
+        }
+        assert!(detector.is_ejected(&backend));
+    }
+
+    #[test]
+    fn test_max_ejection_percent() {
+        let detector = detector("{consecutive_connect_failures: 1}");
+        let a = Backend::new("127.0.0.1:3001").unwrap();
+        let b = Backend::new("127.0.0.1:3002").unwrap();
+
+        detector.report(&a, Outcome::ConnectFailure, 2);
+        detector.report(&b, Outcome::ConnectFailure, 2);
+        // the default 50% keeps one of two servers in rotation
+        assert!(detector.is_ejected(&a));
+        assert!(!detector.is_ejected(&b));
+    }
+
+    #[test]
+    fn test_ejection_time_doubles() {
+        let config: OutlierDetectionConfig =
+            serde_yaml::from_str("{base_ejection_time: 10s, max_ejection_time: 30s}").unwrap();
+        let config = OutlierDetectionResolved::try_from_with_location(&config, "")
+            .unwrap()
+            .unwrap();
+        let mut stats = Stats::default();
+        let now = Instant::now();
+        assert_eq!(stats.eject(&config, now), Duration::from_secs(10));
+        assert_eq!(stats.eject(&config, now), Duration::from_secs(20));
+        assert_eq!(stats.eject(&config, now), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,597 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpSocket, TcpStream, UnixStream},
+};
+
+use crate::conf::ProxyProtocolVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest v1 header, `PROXY TCP6` with two full IPv6 addresses and ports
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// The addresses of the connection a PROXY protocol header was sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ProxyHeader {
+    /// the client
+    pub source: SocketAddr,
+    /// what the client connected to
+    pub destination: SocketAddr,
+}
+
+enum Parsed {
+    /// the header and its length, no addresses for `LOCAL` and `UNKNOWN` connections
+    Header(Option<ProxyHeader>, usize),
+    Incomplete,
+    /// the stream does not start with a header
+    Absent,
+}
+
+/// Read the PROXY protocol header at the start of `stream`. Without `required` a stream that
+/// does not start with one is let through. Returns the addresses of the header, `None` if
+/// there is none or it does not carry any, and the bytes read past it.
+pub(crate) async fn read_header<S>(
+    stream: &mut S,
+    required: bool,
+) -> anyhow::Result<(Option<ProxyHeader>, Bytes)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(V1_MAX_LEN);
+    loop {
+        match parse(&buf)? {
+            Parsed::Header(header, len) => {
+                buf.advance(len);
+                return Ok((header, buf.freeze()));
+            }
+            Parsed::Absent if required => anyhow::bail!("missing PROXY protocol header"),
+            Parsed::Absent => return Ok((None, buf.freeze())),
+            Parsed::Incomplete => {
+                if stream.read_buf(&mut buf).await? == 0 {
+                    anyhow::bail!("connection closed before the PROXY protocol header");
+                }
+            }
+        }
+    }
+}
+
+fn parse(buf: &[u8]) -> anyhow::Result<Parsed> {
+    let prefix = |signature: &[u8]| buf.len() < signature.len() && signature.starts_with(buf);
+    if buf.starts_with(V1_PREFIX) {
+        parse_v1(buf)
+    } else if buf.starts_with(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if prefix(V1_PREFIX) || prefix(V2_SIGNATURE) {
+        Ok(Parsed::Incomplete)
+    } else {
+        Ok(Parsed::Absent)
+    }
+}
+
+// `PROXY TCP4 192.0.2.1 198.51.100.1 51234 443\r\n`
+fn parse_v1(buf: &[u8]) -> anyhow::Result<Parsed> {
+    let Some(end) = buf.windows(2).position(|window| window == b"\r\n") else {
+        anyhow::ensure!(buf.len() < V1_MAX_LEN, "PROXY protocol v1 header too long");
+        return Ok(Parsed::Incomplete);
+    };
+    anyhow::ensure!(end + 2 <= V1_MAX_LEN, "PROXY protocol v1 header too long");
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .context("PROXY protocol v1 header is not ascii")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    let header = match fields.as_slice() {
+        ["UNKNOWN", ..] => None,
+        [
+            family @ ("TCP4" | "TCP6"),
+            source,
+            destination,
+            source_port,
+            destination_port,
+        ] => {
+            let ip = |ip: &str| -> anyhow::Result<IpAddr> {
+                let ip = ip
+                    .parse()
+                    .with_context(|| format!("invalid address `{}`", ip))?;
+                anyhow::ensure!(
+                    matches!(ip, IpAddr::V4(_)) == (*family == "TCP4"),
+                    "address `{}` is not {}",
+                    ip,
+                    family
+                );
+                Ok(ip)
+            };
+            let port = |port: &str| -> anyhow::Result<u16> {
+                port.parse()
+                    .with_context(|| format!("invalid port `{}`", port))
+            };
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            })
+        }
+        _ => anyhow::bail!("invalid PROXY protocol v1 header `{}`", line),
+    };
+    Ok(Parsed::Header(header, end + 2))
+}
+
+fn parse_v2(buf: &[u8]) -> anyhow::Result<Parsed> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+    let (version, command) = (buf[12] >> 4, buf[12] & 0x0f);
+    anyhow::ensure!(
+        version == 2,
+        "unsupported PROXY protocol version {}",
+        version
+    );
+    let len = V2_HEADER_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    let addrs = &buf[V2_HEADER_LEN..len];
+    let header = match (command, buf[13] >> 4) {
+        // LOCAL, the load balancer's own connection, e.g. a health check
+        (0, _) => None,
+        (1, 1) => {
+            anyhow::ensure!(addrs.len() >= 12, "truncated PROXY protocol v2 addresses");
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&addrs[at..at + 4]).unwrap());
+            let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        (1, 2) => {
+            anyhow::ensure!(addrs.len() >= 36, "truncated PROXY protocol v2 addresses");
+            let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&addrs[at..at + 16]).unwrap());
+            let port = |at: usize| u16::from_be_bytes([addrs[at], addrs[at + 1]]);
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        // unix sockets and unspecified families carry no address the proxy can use
+        (1, _) => None,
+        (command, _) => anyhow::bail!("unsupported PROXY protocol v2 command {}", command),
+    };
+    Ok(Parsed::Header(header, len))
+}
+
+/// The header announcing a connection of `addrs`, `LOCAL` (v2) or `UNKNOWN` (v1) without.
+pub(crate) fn encode(version: ProxyProtocolVersion, addrs: Option<ProxyHeader>) -> Vec<u8> {
+    // both ends in one family, IPv4 ends of a dual-stack listener are mapped to IPv6
+    let addrs = addrs.map(
+        |ProxyHeader {
+             source,
+             destination,
+         }| {
+            let (source_ip, destination_ip): (IpAddr, IpAddr) =
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        (source.into(), destination.into())
+                    }
+                    (source, destination) => (to_ipv6(source).into(), to_ipv6(destination).into()),
+                };
+            (
+                SocketAddr::new(source_ip, source.port()),
+                SocketAddr::new(destination_ip, destination.port()),
+            )
+        },
+    );
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let Some((source, destination)) = addrs else {
+                // LOCAL, no address
+                header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+                return header;
+            };
+            let mut body = Vec::with_capacity(36);
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    body.extend_from_slice(&source.octets());
+                    body.extend_from_slice(&destination.octets());
+                    0x11
+                }
+                (source, destination) => {
+                    body.extend_from_slice(&to_ipv6(source).octets());
+                    body.extend_from_slice(&to_ipv6(destination).octets());
+                    0x21
+                }
+            };
+            body.extend_from_slice(&source.port().to_be_bytes());
+            body.extend_from_slice(&destination.port().to_be_bytes());
+            // PROXY over a stream of the family
+            header.extend_from_slice(&[0x21, family]);
+            header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            header.extend_from_slice(&body);
+            header
+        }
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// A stream that yields the bytes read past a PROXY protocol header before its own.
+pub(crate) struct Rewind<S> {
+    prefix: Bytes,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Bytes, inner: S) -> Self {
+        Self { prefix, inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let len = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix.split_to(len));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Connects to a server and sends the PROXY protocol header before anything else. Set as the
+/// `custom_l4` of the peers of upstreams with `proxy_protocol`; connections carry the header
+/// of one downstream connection, the peers of different ones must not share the pool.
+#[derive(Debug)]
+pub(crate) struct ProxyProtocolConnector {
+    header: Vec<u8>,
+    options: ConnectOptions,
+}
+
+/// The options of a peer that pingora only applies in its own connector, it sets keepalive
+/// and nodelay on the stream a custom one returns.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ConnectOptions {
+    pub timeout: Option<Duration>,
+    pub recv_buf: Option<usize>,
+    pub dscp: Option<u8>,
+}
+
+impl From<&pingora::upstreams::peer::PeerOptions> for ConnectOptions {
+    fn from(options: &pingora::upstreams::peer::PeerOptions) -> Self {
+        Self {
+            timeout: options.connection_timeout,
+            recv_buf: options.tcp_recv_buf,
+            dscp: options.dscp,
+        }
+    }
+}
+
+impl From<&pingora_core::upstreams::peer::PeerOptions> for ConnectOptions {
+    fn from(options: &pingora_core::upstreams::peer::PeerOptions) -> Self {
+        Self {
+            timeout: options.connection_timeout,
+            recv_buf: options.tcp_recv_buf,
+            dscp: options.dscp,
+        }
+    }
+}
+
+pub(crate) enum RawStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl From<RawStream> for pingora::protocols::l4::stream::Stream {
+    fn from(stream: RawStream) -> Self {
+        match stream {
+            RawStream::Tcp(tcp) => tcp.into(),
+            RawStream::Unix(unix) => unix.into(),
+        }
+    }
+}
+
+impl From<RawStream> for pingora_core::protocols::l4::stream::Stream {
+    fn from(stream: RawStream) -> Self {
+        match stream {
+            RawStream::Tcp(tcp) => tcp.into(),
+            RawStream::Unix(unix) => unix.into(),
+        }
+    }
+}
+
+impl ProxyProtocolConnector {
+    /// `addrs` of the downstream connection, `None` for health checks and clients on unix
+    /// sockets.
+    pub fn new(
+        version: ProxyProtocolVersion,
+        addrs: Option<ProxyHeader>,
+        options: ConnectOptions,
+    ) -> Self {
+        Self {
+            header: encode(version, addrs),
+            options,
+        }
+    }
+
+    /// Connects without sending a header, for the servers of upstreams whose TLS the proxy
+    /// runs itself, see [crate::proxy::tls::UpstreamTlsConnector].
+    pub fn direct(options: ConnectOptions) -> Self {
+        Self {
+            header: Vec::new(),
+            options,
+        }
+    }
+
+    // like pingora's connector, which sets these on the socket before connecting
+    fn socket(&self, addr: SocketAddr) -> io::Result<TcpSocket> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(size) = self.options.recv_buf {
+            socket.set_recv_buffer_size(size.try_into().unwrap_or(u32::MAX))?;
+        }
+        if let Some(dscp) = self.options.dscp {
+            let tos = u32::from(dscp) << 2;
+            let socket = socket2::SockRef::from(&socket);
+            match addr {
+                SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+                SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+            }
+        }
+        Ok(socket)
+    }
+
+    pub(crate) async fn connect_raw(
+        &self,
+        inet: Option<SocketAddr>,
+        unix: Option<&Path>,
+    ) -> io::Result<RawStream> {
+        let connect = async {
+            let mut stream = match (inet, unix) {
+                (Some(addr), _) => RawStream::Tcp(self.socket(addr)?.connect(addr).await?),
+                (None, Some(path)) => RawStream::Unix(UnixStream::connect(path).await?),
+                (None, None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "unnamed unix sockets are not supported",
+                    ));
+                }
+            };
+            match &mut stream {
+                RawStream::Tcp(tcp) => tcp.write_all(&self.header).await?,
+                RawStream::Unix(unix) => unix.write_all(&self.header).await?,
+            }
+            Ok(stream)
+        };
+        match self.options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?,
+            None => connect.await,
+        }
+    }
+}
+
+#[async_trait]
+impl pingora::connectors::L4Connect for ProxyProtocolConnector {
+    async fn connect(
+        &self,
+        addr: &pingora::protocols::l4::socket::SocketAddr,
+    ) -> pingora::Result<pingora::protocols::l4::stream::Stream> {
+        use pingora::{
+            Error,
+            ErrorType::{ConnectError, ConnectTimedout},
+        };
+
+        let stream = self
+            .connect_raw(
+                addr.as_inet().copied(),
+                addr.as_unix().and_then(|a| a.as_pathname()),
+            )
+            .await
+            .map_err(|e| {
+                let etype = match e.kind() {
+                    io::ErrorKind::TimedOut => ConnectTimedout,
+                    _ => ConnectError,
+                };
+                Error::because(etype, format!("failed to connect to {}", addr), e)
+            })?;
+        Ok(stream.into())
+    }
+}
+
+// health checks go through pingora's load balancing, which has types of its own
+#[async_trait]
+impl pingora_core::connectors::L4Connect for ProxyProtocolConnector {
+    async fn connect(
+        &self,
+        addr: &pingora_core::protocols::l4::socket::SocketAddr,
+    ) -> pingora_error::Result<pingora_core::protocols::l4::stream::Stream> {
+        use pingora_error::{
+            Error,
+            ErrorType::{ConnectError, ConnectTimedout},
+        };
+
+        let stream = self
+            .connect_raw(
+                addr.as_inet().copied(),
+                addr.as_unix().and_then(|a| a.as_pathname()),
+            )
+            .await
+            .map_err(|e| {
+                let etype = match e.kind() {
+                    io::ErrorKind::TimedOut => ConnectTimedout,
+                    _ => ConnectError,
+                };
+                Error::because(etype, format!("failed to connect to {}", addr), e)
+            })?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(source: &str, destination: &str) -> ProxyHeader {
+        ProxyHeader {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_header() -> anyhow::Result<()> {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 51234 443\r\nGET / HTTP/1.1\r\n";
+        let (parsed, rest) = read_header(&mut stream, true).await?;
+        assert_eq!(parsed, Some(header("192.0.2.1:51234", "198.51.100.1:443")));
+        assert!(b"GET / HTTP/1.1\r\n".starts_with(&rest));
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream, true).await?.0, None);
+
+        // without a header the bytes read are handed back
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        let (parsed, rest) = read_header(&mut stream, false).await?;
+        assert_eq!((parsed, rest.as_ref()), (None, &b"GET / HTTP/1.1\r\n"[..]));
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut stream, true).await.is_err());
+
+        let mut stream: &[u8] = b"PROXY TCP4 2001:db8::1 198.51.100.1 51234 443\r\n";
+        assert!(read_header(&mut stream, false).await.is_err());
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1";
+        assert!(read_header(&mut stream, false).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_encode_round_trip() -> anyhow::Result<()> {
+        let cases = [
+            Some(header("192.0.2.1:51234", "198.51.100.1:443")),
+            Some(header("[2001:db8::1]:51234", "[2001:db8::2]:443")),
+            None,
+        ];
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for addrs in cases {
+                let mut encoded = encode(version, addrs);
+                encoded.extend_from_slice(b"GET /");
+                let (parsed, rest) = read_header(&mut encoded.as_slice(), true).await?;
+                assert_eq!(parsed, addrs);
+                assert!(b"GET /".starts_with(&rest));
+            }
+        }
+
+        // an IPv4 client of a dual-stack listener
+        let encoded = encode(
+            ProxyProtocolVersion::V2,
+            Some(header("192.0.2.1:51234", "[::1]:443")),
+        );
+        let (parsed, _) = read_header(&mut encoded.as_slice(), true).await?;
+        assert_eq!(
+            parsed,
+            Some(header("[::ffff:192.0.2.1]:51234", "[::1]:443"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connector() -> anyhow::Result<()> {
+        use pingora::{connectors::L4Connect, protocols::l4::socket::SocketAddr as PeerAddr};
+
+        let addrs = header("192.0.2.1:51234", "198.51.100.1:443");
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let options = ConnectOptions {
+            timeout: Some(Duration::from_secs(1)),
+            recv_buf: Some(256 * 1024),
+            dscp: Some(10),
+        };
+        let connector = ProxyProtocolConnector::new(ProxyProtocolVersion::V2, Some(addrs), options);
+
+        // the socket options of the peer are set and the header goes first
+        let RawStream::Tcp(stream) = connector.connect_raw(Some(addr), None).await? else {
+            panic!("expected a tcp stream");
+        };
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.recv_buffer_size()? >= 256 * 1024);
+        assert_eq!(socket.tos_v4()?, 10 << 2);
+        let (mut accepted, _) = listener.accept().await?;
+        assert_eq!(read_header(&mut accepted, true).await?.0, Some(addrs));
+
+        // once the accept queue is full further connects hang until they time out
+        let socket = TcpSocket::new_v4()?;
+        socket.bind("127.0.0.1:0".parse()?)?;
+        let full = socket.listen(1)?;
+        let addr = full.local_addr()?;
+        let mut queued = Vec::new();
+        for _ in 0..16 {
+            let connect =
+                tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr));
+            match connect.await {
+                Ok(stream) => queued.push(stream?),
+                Err(_) => break,
+            }
+        }
+        let options = ConnectOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let connector = ProxyProtocolConnector::new(ProxyProtocolVersion::V1, None, options);
+        let e = connector
+            .connect(&PeerAddr::Inet(addr))
+            .await
+            .expect_err("the accept queue is full");
+        assert_eq!(e.etype(), &pingora::ErrorType::ConnectTimedout);
+        Ok(())
+    }
+}
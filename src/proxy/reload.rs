@@ -0,0 +1,374 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+#[cfg(unix)]
+use pingora::server::ListenFds;
+use pingora::{server::ShutdownWatch, services::Service};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::mpsc,
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    conf::{ProxyConfig, SimpleProxyConfigResolved},
+    proxy::{balancer::ResolvedAddrs, listener::ListenerTls, route::RouteTable},
+};
+
+// editors usually emit several events per save, wait for them to settle before reloading
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(200);
+
+// how often the certificate reloader looks for a config reload
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct ConfigReloader {
+    path: PathBuf,
+    config: ProxyConfig,
+    route_table: RouteTable,
+}
+
+impl ConfigReloader {
+    pub fn new(path: impl Into<PathBuf>, config: ProxyConfig, route_table: RouteTable) -> Self {
+        Self {
+            path: path.into(),
+            config,
+            route_table,
+        }
+    }
+
+    /// Re-read the config file and apply it. On error the current config keeps serving.
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        let config = SimpleProxyConfigResolved::load(&self.path)?;
+
+        let current = self.config.get();
+        if *current == config {
+            info!("config {} unchanged", self.path.display());
+            return Ok(());
+        }
+        // certificates are picked up by the certificate reloader
+        if current.global.listeners != config.global.listeners
+            || current.global.tls.as_ref().map(|tls| &tls.ca)
+                != config.global.tls.as_ref().map(|tls| &tls.ca)
+        {
+            warn!(
+                "listener config changed, the listeners and the upstream ca are only applied on restart"
+            );
+        }
+
+        // resolved on tokio's blocking pool, updating the table itself never waits for DNS
+        let addrs = ResolvedAddrs::lookup(config.upstreams()).await?;
+        self.route_table.update(&config, &addrs)?;
+        self.config.update(config);
+        Ok(())
+    }
+}
+
+/// Send on `tx` when any of `files` is written or replaced.
+fn watch_files<'a>(
+    files: impl IntoIterator<Item = &'a Path>,
+    tx: mpsc::UnboundedSender<()>,
+) -> notify::Result<RecommendedWatcher> {
+    // watch the parent directories, editors and k8s configmaps replace files on save
+    let mut dirs: BTreeMap<&Path, BTreeSet<OsString>> = BTreeMap::new();
+    for file in files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        if let Some(name) = file.file_name() {
+            dirs.entry(dir).or_default().insert(name.to_owned());
+        }
+    }
+    let names: BTreeSet<OsString> = dirs.values().flatten().cloned().collect();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let Ok(event) = res else {
+            return;
+        };
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            return;
+        }
+        if event
+            .paths
+            .iter()
+            .any(|path| path.file_name().is_some_and(|name| names.contains(name)))
+        {
+            tx.send(()).ok();
+        }
+    })?;
+    for dir in dirs.keys() {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+#[async_trait]
+impl Service for ConfigReloader {
+    async fn start_service(
+        &mut self,
+        #[cfg(unix)] _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let _watcher = match watch_files([self.path.as_path()], tx) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                error!("failed to watch {}: {}", self.path.display(), e);
+                None
+            }
+        };
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+
+        loop {
+            tokio::select! {
+                Some(()) = rx.recv() => {
+                    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                    while rx.try_recv().is_ok() {}
+                    info!("config {} changed on disk, reloading", self.path.display());
+                }
+                _ = hangup.recv() => {
+                    info!("received SIGHUP, reloading config {}", self.path.display());
+                }
+                _ = shutdown.changed() => break,
+            }
+
+            match self.reload().await {
+                Ok(()) => info!("config {} reloaded", self.path.display()),
+                Err(e) => error!(
+                    "failed to reload config {}, keep serving the old one: {:#}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "config-reloader"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Reloads the certificates, keys, client CA and CRL of the TLS listener when their files
+/// change, on SIGHUP and after a config reload. A certificate that fails to load, does not
+/// match its key or has expired keeps the old ones serving.
+pub struct CertReloader {
+    config: ProxyConfig,
+    tls: Arc<ArcSwap<ListenerTls>>,
+}
+
+impl CertReloader {
+    pub(crate) fn new(config: ProxyConfig, tls: Arc<ArcSwap<ListenerTls>>) -> Self {
+        Self { config, tls }
+    }
+
+    /// Load the certificates of the current config and serve new handshakes with them.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let tls = ListenerTls::new(&self.config.get(), true)?;
+        self.tls.store(Arc::new(tls));
+        Ok(())
+    }
+
+    fn watch(
+        &self,
+        config: &SimpleProxyConfigResolved,
+        tx: mpsc::UnboundedSender<()>,
+    ) -> Option<RecommendedWatcher> {
+        let files = ListenerTls::files(config);
+        match watch_files(files.iter().map(PathBuf::as_path), tx) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                error!("failed to watch certificates {:?}: {}", files, e);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Service for CertReloader {
+    async fn start_service(
+        &mut self,
+        #[cfg(unix)] _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+    ) {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut applied = self.config.get();
+        let mut _watcher = self.watch(&applied, tx.clone());
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!("failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(CONFIG_CHECK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Some(()) = rx.recv() => {
+                    tokio::time::sleep(RELOAD_DEBOUNCE).await;
+                    while rx.try_recv().is_ok() {}
+                    info!("certificates changed on disk, reloading");
+                }
+                _ = hangup.recv() => {
+                    info!("received SIGHUP, reloading certificates");
+                }
+                _ = interval.tick() => {
+                    // a config reload may change the files or the servers they are served for
+                    let config = self.config.get();
+                    if Arc::ptr_eq(&config, &applied) {
+                        continue;
+                    }
+                    if ListenerTls::files(&config) != ListenerTls::files(&applied) {
+                        _watcher = self.watch(&config, tx.clone());
+                    }
+                    applied = config;
+                    info!("config reloaded, reloading certificates");
+                }
+                _ = shutdown.changed() => break,
+            }
+
+            match self.reload() {
+                Ok(()) => info!("certificates reloaded"),
+                Err(e) => error!(
+                    "failed to reload certificates, keep serving the old ones: {:#}",
+                    e
+                ),
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "cert-reloader"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::http::RequestHeader;
+
+    fn yaml(servers: &[&str]) -> String {
+        let mut yaml = "global:\n  port: 3000\nservers:\n".to_string();
+        for name in servers {
+            yaml.push_str(&format!("  - server_name: [{}]\n    upstream: web\n", name));
+        }
+        yaml.push_str("upstreams:\n  - name: web\n    servers: [\"127.0.0.1:3001\"]\n");
+        yaml
+    }
+
+    // a config file per test, the tests run in parallel
+    fn reloader(name: &str, yaml: &str) -> ConfigReloader {
+        let path = std::env::temp_dir().join(format!(
+            "simple-proxy-test-{}-{}.yml",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, yaml).unwrap();
+        let config = ProxyConfig::load(&path).unwrap();
+        let route_table = RouteTable::new(&config.get()).unwrap();
+        ConfigReloader::new(path, config, route_table)
+    }
+
+    fn upstream(table: &RouteTable, host: &str) -> Option<String> {
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        table
+            .lookup(host, &req, None)
+            .map(|entry| entry.config.name)
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let reloader = reloader("reload", &yaml(&["acme.com"]));
+        let before = reloader.config.get();
+        reloader.reload().await.unwrap();
+        assert!(Arc::ptr_eq(&before, &reloader.config.get()));
+
+        std::fs::write(&reloader.path, yaml(&["globex.com"])).unwrap();
+        reloader.reload().await.unwrap();
+        let after = reloader.config.get();
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert!(after.servers.contains_key("globex.com"));
+        assert_eq!(
+            upstream(&reloader.route_table, "globex.com").as_deref(),
+            Some("web")
+        );
+        assert_eq!(upstream(&reloader.route_table, "acme.com"), None);
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_invalid() {
+        let reloader = reloader("reload-invalid", &yaml(&["acme.com"]));
+        let before = reloader.config.get();
+
+        let invalid = yaml(&["globex.com"]).replace("upstream: web", "upstream: missing");
+        for yaml in [invalid.as_str(), "servers: [", ""] {
+            std::fs::write(&reloader.path, yaml).unwrap();
+            assert!(reloader.reload().await.is_err());
+            assert!(Arc::ptr_eq(&before, &reloader.config.get()));
+            assert_eq!(
+                upstream(&reloader.route_table, "acme.com").as_deref(),
+                Some("web")
+            );
+            assert_eq!(upstream(&reloader.route_table, "globex.com"), None);
+        }
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_under_lookups() {
+        let reloader = reloader("reload-lookups", &yaml(&["acme.com"]));
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let lookups: Vec<_> = (0..4)
+            .map(|_| {
+                let (table, done) = (reloader.route_table.clone(), done.clone());
+                std::thread::spawn(move || {
+                    while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                        // the server is in every config, it must never go missing
+                        assert_eq!(upstream(&table, "acme.com").as_deref(), Some("web"));
+                        upstream(&table, "globex.com");
+                    }
+                })
+            })
+            .collect();
+
+        for i in 0..50 {
+            let servers: &[&str] = if i % 2 == 0 {
+                &["acme.com", "globex.com", "*.initech.com"]
+            } else {
+                &["acme.com"]
+            };
+            std::fs::write(&reloader.path, yaml(servers)).unwrap();
+            reloader.reload().await.unwrap();
+        }
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        for lookup in lookups {
+            lookup.join().unwrap();
+        }
+        assert_eq!(upstream(&reloader.route_table, "globex.com"), None);
+        assert_eq!(upstream(&reloader.route_table, "www.initech.com"), None);
+        std::fs::remove_file(&reloader.path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::Method;
+
+// budgets are counted over fixed windows of this length
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+// retries allowed per window regardless of a non-zero budget, so that an upstream with
+// little traffic still gets its connect failures retried
+const MIN_RETRIES: u32 = 3;
+
+/// Caps the retries of an upstream to a share of its requests, see `retry.budget` in the
+/// upstream config. Keeps retries from multiplying the load on an upstream that is already
+/// failing.
+pub(crate) struct RetryBudget {
+    budget: f64,
+    window: Mutex<Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+impl RetryBudget {
+    pub fn new(budget: f64) -> Self {
+        Self {
+            budget,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    /// Count a request, retries are not requests.
+    pub fn request(&self) {
+        self.current(Instant::now()).requests += 1;
+    }
+
+    /// Take a retry out of the budget, `false` if it is spent. A budget of 0 allows none.
+    pub fn try_retry(&self) -> bool {
+        if self.budget <= 0.0 {
+            return false;
+        }
+        let mut window = self.current(Instant::now());
+        let allowed = (window.requests as f64 * self.budget) as u32;
+        if window.retries >= allowed.max(MIN_RETRIES) {
+            return false;
+        }
+        window.retries += 1;
+        true
+    }
+
+    fn current(&self, now: Instant) -> std::sync::MutexGuard<'_, Window> {
+        let mut window = self.window.lock().expect("retry budget poisoned");
+        if now.duration_since(window.start) >= BUDGET_WINDOW {
+            *window = Window {
+                start: now,
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+}
+
+/// Whether sending the request twice has the same effect as sending it once.
+pub(crate) fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(0.2);
+        for _ in 0..MIN_RETRIES {
+            assert!(budget.try_retry());
+        }
+        assert!(!budget.try_retry());
+
+        // 20% of 50 requests
+        for _ in 0..50 {
+            budget.request();
+        }
+        for _ in MIN_RETRIES..10 {
+            assert!(budget.try_retry());
+        }
+        assert!(!budget.try_retry());
+
+        let budget = RetryBudget::new(0.0);
+        for _ in 0..50 {
+            budget.request();
+        }
+        assert!(!budget.try_retry());
+    }
+}
@@ -1,58 +1,468 @@
-use std::{sync::Arc, time::Duration};
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use papaya::HashMap;
-use pingora_load_balancing::{Backend, LoadBalancer, health_check, selection::RoundRobin};
+use pingora::http::RequestHeader;
+use pingora_load_balancing::Backend;
+use tracing::info;
 
-use crate::conf::{ServerConfigResolved, SimpleProxyConfigResolved};
+use crate::{
+    conf::{
+        ClientAuth, ForwardedHeadersResolved, HeaderRewriteResolved, HstsResolved,
+        LocationConfigResolved, RedirectToHttpsResolved, ServerConfigResolved,
+        SimpleProxyConfigResolved, UpstreamConfigResolved,
+    },
+    proxy::{
+        balancer::{Balancer, ResolvedAddrs},
+        headers::HeaderRewrite,
+        matcher::{HostMatcher, PathMatcher, RequestMatcher},
+    },
+};
 
-const HEALTH_CHECK_FREQUENCY: Duration = Duration::from_secs(10);
+/// Server routes keyed by `server_name` as written in the config, e.g. `acme.com`,
+/// `*.acme.com` or `~^api\d+`, and the upstreams they route to keyed by name.
 #[derive(Clone)]
-pub struct RouteTable(pub(crate) Arc<HashMap<String, RouteEntry>>);
+pub struct RouteTable {
+    pub(crate) servers: Arc<HashMap<String, ServerRoute>>,
+    pub(crate) upstreams: Arc<HashMap<String, UpstreamRoute>>,
+    hosts: Arc<ArcSwap<HostMatcher>>,
+}
 
 impl RouteTable {
+    /// Resolves the upstream servers blocking the thread, so it is called outside the async
+    /// runtime; reloads look them up beforehand and go through [`RouteTable::update`].
     pub fn new(config: &SimpleProxyConfigResolved) -> anyhow::Result<Self> {
-        let route_table = HashMap::new();
-        {
-            let map = route_table.pin();
-            for (name, server) in config.servers.iter() {
-                map.insert(name.clone(), RouteEntry::new(server)?);
+        let route_table = Self {
+            servers: Arc::new(HashMap::new()),
+            upstreams: Arc::new(HashMap::new()),
+            hosts: Arc::new(ArcSwap::from_pointee(HostMatcher::default())),
+        };
+        route_table.update(config, &ResolvedAddrs::resolve(config.upstreams())?)?;
+        Ok(route_table)
+    }
+
+    pub fn upstreams(&self) -> &HashMap<String, UpstreamRoute> {
+        &self.upstreams
+    }
+
+    /// Patch the table in place to match `config`. Routes of unchanged servers and upstreams
+    /// are kept (along with their health state); nothing is touched if any new route fails to
+    /// build. New upstreams take the addresses of their servers from `addrs`.
+    pub fn update(
+        &self,
+        config: &SimpleProxyConfigResolved,
+        addrs: &ResolvedAddrs,
+    ) -> anyhow::Result<()> {
+        let map = self.pin();
+        let upstream_map = self.upstreams.pin();
+        let hosts = HostMatcher::new(config)?;
+
+        // an upstream is probed over tls if any route to it uses tls
+        let mut referenced = std::collections::HashMap::new();
+        for server in config.servers.values() {
+            let routes = std::iter::once((&server.upstream, server.tls)).chain(
+                server
+                    .locations
+                    .iter()
+                    .map(|location| (&location.upstream, location.tls)),
+            );
+            for (upstream, tls) in routes {
+                referenced
+                    .entry(upstream.name.as_str())
+                    .and_modify(|(_, any_tls)| *any_tls |= tls)
+                    .or_insert((upstream, tls));
+            }
+        }
+        let mut upstreams = std::collections::HashMap::new();
+        for (name, (upstream, tls)) in referenced {
+            let route = match upstream_map.get(name) {
+                Some(route) if route.config == *upstream && route.tls == tls => route.clone(),
+                _ => UpstreamRoute::new(upstream, tls, addrs)?,
+            };
+            upstreams.insert(name.to_string(), route);
+        }
+
+        let mut changed = Vec::new();
+        for (name, server) in config.servers.iter() {
+            match map.get(name) {
+                Some(route) if route.config == *server && route.shares(&upstreams) => {}
+                _ => changed.push((name.clone(), ServerRoute::new(server, &upstreams)?)),
+            }
+        }
+
+        // insert before swapping the matcher and remove after, so that a name the matcher
+        // returns always has a route
+        for (name, upstream) in upstreams.iter() {
+            let unchanged = upstream_map
+                .get(name)
+                .is_some_and(|route| Arc::ptr_eq(&route.balancer, &upstream.balancer));
+            if !unchanged {
+                info!("route table: update upstream {}", name);
+                upstream_map.insert(name.clone(), upstream.clone());
             }
         }
-        Ok(Self(Arc::new(route_table)))
+        for (name, route) in changed {
+            info!(
+                "route table: update {} -> {}",
+                name, route.config.upstream.name
+            );
+            map.insert(name, route);
+        }
+        self.hosts.store(Arc::new(hosts));
+
+        let removed: Vec<String> = map
+            .keys()
+            .filter(|name| !config.servers.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in removed {
+            info!("route table: remove {}", name);
+            map.remove(&name);
+        }
+        let removed: Vec<String> = upstream_map
+            .keys()
+            .filter(|name| !upstreams.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in removed {
+            info!("route table: remove upstream {}", name);
+            upstream_map.remove(&name);
+        }
+        Ok(())
+    }
+
+    /// Find the route of a request: the most specific server reachable on `listener` (any
+    /// listener if `None`) whose `match` block holds, then within it the first matching
+    /// location, falling back to the server's own upstream.
+    pub fn lookup(
+        &self,
+        host: &str,
+        req: &RequestHeader,
+        listener: Option<&str>,
+    ) -> Option<RouteEntry> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let map = self.pin();
+        // wildcard and regex names are keys of the map too, they must not match literally
+        let exact = if !host.contains('*') && !host.starts_with('~') {
+            map.get(&host)
+        } else {
+            None
+        };
+        let hosts = self.hosts.load();
+        exact
+            .into_iter()
+            .chain(hosts.find(&host).filter_map(|name| map.get(name)))
+            .filter(|server| listener.is_none_or(|listener| server.config.listens_on(listener)))
+            .find_map(|server| server.route(req))
+            .cloned()
     }
 }
 
 impl std::ops::Deref for RouteTable {
-    type Target = HashMap<String, RouteEntry>;
+    type Target = HashMap<String, ServerRoute>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.servers
+    }
+}
+
+#[derive(Clone)]
+pub struct ServerRoute {
+    pub config: ServerConfigResolved,
+    pub default: RouteEntry,
+    matcher: Option<Arc<RequestMatcher>>,
+    locations: Arc<PathMatcher<LocationRoute>>,
+}
+
+struct LocationRoute {
+    matcher: Option<RequestMatcher>,
+    entry: RouteEntry,
+}
+
+impl ServerRoute {
+    pub fn new(
+        config: &ServerConfigResolved,
+        upstreams: &std::collections::HashMap<String, UpstreamRoute>,
+    ) -> anyhow::Result<Self> {
+        // the server's header rules followed by the location's
+        fn headers(
+            server: &HeaderRewriteResolved,
+            location: Option<&HeaderRewriteResolved>,
+        ) -> anyhow::Result<Arc<HeaderRewrite>> {
+            let configs: Vec<_> = std::iter::once(server).chain(location).collect();
+            HeaderRewrite::new(&configs).map(Arc::new)
+        }
+        let entry = |upstream: &UpstreamConfigResolved,
+                     tls: bool,
+                     location: Option<&LocationConfigResolved>|
+         -> anyhow::Result<_> {
+            let route = upstreams
+                .get(&upstream.name)
+                .ok_or_else(|| anyhow::anyhow!("upstream {} not found", upstream.name))?;
+            Ok(RouteEntry {
+                upstream: Arc::clone(&route.balancer),
+                tls,
+                client_auth: config.client_auth,
+                redirect_to_https: config.redirect_to_https,
+                hsts: config.hsts.clone(),
+                forwarded_headers: config.forwarded_headers,
+                request_headers: headers(
+                    &config.request_headers,
+                    location.map(|location| &location.request_headers),
+                )?,
+                response_headers: headers(
+                    &config.response_headers,
+                    location.map(|location| &location.response_headers),
+                )?,
+                config: upstream.clone(),
+            })
+        };
+
+        let default = entry(&config.upstream, config.tls, None)?;
+        let mut locations = PathMatcher::default();
+        for location in config.locations.iter() {
+            let route = LocationRoute {
+                matcher: location
+                    .matches
+                    .as_ref()
+                    .map(RequestMatcher::new)
+                    .transpose()?,
+                entry: entry(&location.upstream, location.tls, Some(location))?,
+            };
+            locations.insert(&location.path, route)?;
+        }
+        let matcher = config
+            .matches
+            .as_ref()
+            .map(RequestMatcher::new)
+            .transpose()?;
+        Ok(Self {
+            config: config.clone(),
+            default,
+            matcher: matcher.map(Arc::new),
+            locations: Arc::new(locations),
+        })
+    }
+
+    /// `None` if the server's own `match` block does not hold for the request.
+    pub fn route(&self, req: &RequestHeader) -> Option<&RouteEntry> {
+        if let Some(matcher) = &self.matcher
+            && !matcher.matches(req)
+        {
+            return None;
+        }
+        let location = self.locations.find(req.uri.path()).find(|location| {
+            location
+                .matcher
+                .as_ref()
+                .is_none_or(|matcher| matcher.matches(req))
+        });
+        Some(location.map_or(&self.default, |location| &location.entry))
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &RouteEntry> {
+        std::iter::once(&self.default)
+            .chain(self.locations.values().map(|location| &location.entry))
+    }
+
+    // whether every entry uses the load balancer `upstreams` holds for its upstream
+    fn shares(&self, upstreams: &std::collections::HashMap<String, UpstreamRoute>) -> bool {
+        self.entries().all(|entry| {
+            upstreams
+                .get(&entry.config.name)
+                .is_some_and(|route| Arc::ptr_eq(&route.balancer, &entry.upstream))
+        })
+    }
+}
+
+/// Load balancer of an upstream, shared by every server and location routing to it.
+#[derive(Clone)]
+pub struct UpstreamRoute {
+    pub config: UpstreamConfigResolved,
+    pub tls: bool,
+    pub balancer: Arc<Balancer>,
+}
+
+impl UpstreamRoute {
+    pub fn new(
+        config: &UpstreamConfigResolved,
+        tls: bool,
+        addrs: &ResolvedAddrs,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            config: config.clone(),
+            tls,
+            balancer: Arc::new(Balancer::new(config, tls, addrs)?),
+        })
     }
 }
 
 #[derive(Clone)]
 pub struct RouteEntry {
-    pub upstream: Arc<LoadBalancer<RoundRobin>>,
+    pub upstream: Arc<Balancer>,
     pub tls: bool,
+    /// of the server the entry belongs to
+    pub client_auth: ClientAuth,
+    pub redirect_to_https: Option<RedirectToHttpsResolved>,
+    pub hsts: Option<HstsResolved>,
+    pub forwarded_headers: ForwardedHeadersResolved,
+    pub(crate) request_headers: Arc<HeaderRewrite>,
+    pub(crate) response_headers: Arc<HeaderRewrite>,
+    pub config: UpstreamConfigResolved,
 }
 
 impl RouteEntry {
-    const MAX_BACKEND_ITER: usize = 32;
-    pub fn new(config: &ServerConfigResolved) -> anyhow::Result<Self> {
-        let mut lb =
-            LoadBalancer::try_from_iter(config.upstream.servers.iter().map(|s| s.to_string()))?;
-        let hc = health_check::TcpHealthCheck::new();
-        lb.set_health_check(hc);
-        lb.health_check_frequency = Some(HEALTH_CHECK_FREQUENCY);
+    pub(crate) fn select(&self, key: &[u8], tried: &[Backend]) -> Option<Backend> {
+        self.upstream.select(key, tried)
+    }
 
-        Ok(Self {
-            upstream: Arc::new(lb),
-            tls: config.tls,
-        })
+    pub(crate) fn release(&self, backend: &Backend) {
+        self.upstream.release(backend)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::SimpleProxyConfig;
+
+    fn config() -> anyhow::Result<SimpleProxyConfigResolved> {
+        let yaml = r#"
+global:
+  port: 3000
+servers:
+  - server_name: [api.acme.com]
+    upstream: replicas
+    match:
+      headers:
+        X-Tenant: acme
+    locations:
+      - path: /v1/
+        upstream: primary
+        match:
+          method: [POST, PUT, DELETE]
+      - path: /v1/
+        upstream: replicas
+  - server_name: ["*.acme.com", "acme.com"]
+    upstream: web_servers
+upstreams:
+  - name: primary
+    servers: ["127.0.0.1:3001"]
+  - name: replicas
+    servers: ["127.0.0.1:3002"]
+  - name: web_servers
+    servers: ["127.0.0.1:3003"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        Ok(SimpleProxyConfigResolved::try_from(config)?)
+    }
+
+    fn route_table() -> anyhow::Result<RouteTable> {
+        RouteTable::new(&config()?)
+    }
+
+    fn upstream(table: &RouteTable, method: &str, tenant: Option<&str>) -> Option<String> {
+        let mut req = RequestHeader::build(method, b"/v1/users", None).unwrap();
+        if let Some(tenant) = tenant {
+            req.insert_header("x-tenant", tenant).unwrap();
+        }
+        table
+            .lookup("api.acme.com", &req, None)
+            .map(|entry| entry.config.name)
+    }
+
+    #[test]
+    fn test_lookup_match_precedence() -> anyhow::Result<()> {
+        let table = route_table()?;
+        assert_eq!(
+            upstream(&table, "POST", Some("acme")).as_deref(),
+            Some("primary")
+        );
+        assert_eq!(
+            upstream(&table, "GET", Some("acme")).as_deref(),
+            Some("replicas")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_match_fallthrough() -> anyhow::Result<()> {
+        let table = route_table()?;
+        // the exact server does not match, the wildcard one takes over
+        assert_eq!(
+            upstream(&table, "POST", Some("globex")).as_deref(),
+            Some("web_servers")
+        );
+        assert_eq!(
+            upstream(&table, "GET", None).as_deref(),
+            Some("web_servers")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lookup_by_listener() -> anyhow::Result<()> {
+        let yaml = r#"
+global:
+  listeners:
+    - name: public
+      port: 80
+    - name: internal
+      address: 127.0.0.1
+      port: 8080
+servers:
+  - server_name: [api.acme.com]
+    upstream: primary
+    listeners: [internal]
+  - server_name: ["*.acme.com"]
+    upstream: web_servers
+upstreams:
+  - name: primary
+    servers: ["127.0.0.1:3001"]
+  - name: web_servers
+    servers: ["127.0.0.1:3003"]
+"#;
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let table = RouteTable::new(&SimpleProxyConfigResolved::try_from(config)?)?;
+        let req = RequestHeader::build("GET", b"/", None)?;
+        let upstream = |listener| {
+            table
+                .lookup("api.acme.com", &req, listener)
+                .map(|entry| entry.config.name)
+        };
+        // servers not reachable on the listener are skipped like non-matching ones
+        assert_eq!(upstream(Some("public")).as_deref(), Some("web_servers"));
+        assert_eq!(upstream(Some("internal")).as_deref(), Some("primary"));
+        assert_eq!(upstream(None).as_deref(), Some("primary"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_upstreams_are_shared() -> anyhow::Result<()> {
+        let table = route_table()?;
+        let balancer = |name: &str| {
+            let upstreams = table.upstreams().pin();
+            Arc::clone(&upstreams.get(name).unwrap().balancer)
+        };
+        let (replicas, web_servers) = (balancer("replicas"), balancer("web_servers"));
+        assert_eq!(table.upstreams().len(), 3);
+
+        let servers = table.pin();
+        let api = servers.get("api.acme.com").unwrap();
+        assert!(
+            api.entries()
+                .filter(|entry| entry.config.name == "replicas")
+                .all(|entry| Arc::ptr_eq(&entry.upstream, &replicas))
+        );
+        for name in ["*.acme.com", "acme.com"] {
+            let server = servers.get(name).unwrap();
+            assert!(Arc::ptr_eq(&server.default.upstream, &web_servers));
+        }
 
-    pub(crate) fn select(&self, _host: &str) -> Option<Backend> {
-        self.upstream
-            .select_with(b"", Self::MAX_BACKEND_ITER, |_b, health| health)
+        // reloading the same config keeps the load balancers and their health state
+        let config = config()?;
+        table.update(&config, &ResolvedAddrs::resolve(config.upstreams())?)?;
+        assert!(Arc::ptr_eq(&balancer("replicas"), &replicas));
+        Ok(())
     }
 }
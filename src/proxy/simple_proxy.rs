@@ -1,8 +1,20 @@
 use crate::{
-    conf::ProxyConfig,
+    conf::{
+        ClientAuth, ListenerConfigResolved, ProxyConfig, RedirectToHttpsResolved, RetryOn,
+        UpstreamTimeouts,
+    },
     proxy::{
+        forwarded::ForwardedRequest,
+        headers::HeaderVars,
+        listener::{Downstream, DownstreamTls, Downstreams},
+        outlier::Outcome,
+        proxy_protocol::{ProxyHeader, ProxyProtocolConnector},
+        retry::is_idempotent,
         route::{RouteEntry, RouteTable},
-        utils::get_session_host_port,
+        utils::{
+            get_client_addr, get_hash_key, get_real_ip, get_server_addr, get_session_host_port,
+            https_location,
+        },
     },
 };
 use async_trait::async_trait;
@@ -18,11 +30,21 @@ use pingora::{
     protocols::http::conditional_filter,
     proxy::PurgeStatus,
 };
-use std::time::Duration;
+use pingora_load_balancing::Backend;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tracing::info;
+#[derive(Clone)]
 pub struct SimpleProxy {
     pub(crate) config: ProxyConfig,
     pub(crate) route_table: RouteTable,
+    pub(crate) downstreams: Downstreams,
+    // the listener requests come in on, servers not reachable on it are skipped
+    pub(crate) listener: Option<ListenerConfigResolved>,
 }
 
 #[derive(Default)]
@@ -30,6 +52,190 @@ pub struct ProxyContext {
     host: String,
     port: u16,
     entry: Option<RouteEntry>,
+    // what a hashing upstream selects the backend by
+    hash_key: Vec<u8>,
+    // held against the upstream's connection count until released
+    backend: Option<Backend>,
+    // every backend selected so far, a retry prefers the others
+    tries: Vec<Backend>,
+    // the response header went out, nothing can be retried past this
+    responded: bool,
+    // when the request came in, the upstream's request_timeout counts from here
+    started: Option<Instant>,
+    // cuts the connection of the current attempt at the request_timeout
+    deadline: Option<Deadline>,
+    // the connection as accepted by a relay listener
+    downstream: Option<Arc<Downstream>>,
+    // whether the request came over tls, through a relay listener or pingora's
+    tls: bool,
+    // the ends of the downstream connection, the client as told by the PROXY protocol header
+    // on listeners that read one
+    client_addr: Option<SocketAddr>,
+    server_addr: Option<SocketAddr>,
+    // the client behind the trusted proxies of `real_ip`, the connection's otherwise
+    client_ip: Option<IpAddr>,
+    // `$request_id` of header rewrite rules
+    request_id: String,
+}
+
+impl ProxyContext {
+    /// The address of the client, `None` for clients on a unix socket.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    /// The address of the client the request was sent by, behind the trusted proxies of
+    /// `real_ip`. `None` for clients on a unix socket.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    // `None` if the request did not come over tls through a relay listener
+    fn downstream_tls(&self) -> Option<&DownstreamTls> {
+        self.downstream.as_ref()?.tls.as_ref()
+    }
+
+    // what the variables of header rewrite rules expand to
+    fn header_vars(&self) -> HeaderVars<'_> {
+        let upstream_addr = self
+            .backend
+            .as_ref()
+            .map(|backend| match backend.addr.as_unix() {
+                Some(_) => format!("unix:{}", backend.addr),
+                None => backend.addr.to_string(),
+            });
+        HeaderVars {
+            client_ip: self.client_ip,
+            host: &self.host,
+            tls: self.tls,
+            request_id: &self.request_id,
+            upstream_addr,
+        }
+    }
+
+    // feed the outcome of the request to the upstream's outlier detection
+    fn report(&self, outcome: Outcome) {
+        if let (Some(entry), Some(backend)) = (self.entry.as_ref(), self.backend.as_ref()) {
+            entry.upstream.report(backend, outcome);
+        }
+    }
+
+    // whether the upstream's retry policy allows another attempt after `failure`, a retryable
+    // status is passed as `None`
+    fn should_retry(&self, session: &Session, failure: Option<RetryOn>) -> bool {
+        let Some(entry) = self.entry.as_ref() else {
+            return false;
+        };
+        let retry = &entry.config.retry;
+        let retryable = failure.is_none_or(|failure| retry.retry_on.contains(&failure));
+        // nothing reached the server if it could not be connected to
+        let safe = failure == Some(RetryOn::ConnectFailure)
+            || !retry.idempotent_only
+            || is_idempotent(&session.req_header().method);
+        retryable
+            && safe
+            && !self.responded
+            && self.tries.len() < retry.attempts
+            && !session.as_ref().retry_buffer_truncated()
+            && self
+                .remaining()
+                .is_none_or(|remaining| !remaining.is_zero())
+            && entry.upstream.retry_budget().try_retry()
+    }
+
+    // what is left of the upstream's request_timeout
+    fn remaining(&self) -> Option<Duration> {
+        let timeout = self.entry.as_ref()?.config.timeouts.request_timeout?;
+        Some(timeout.saturating_sub(self.started?.elapsed()))
+    }
+
+    // the request_timeout passed, whatever failed afterwards was cut off by it
+    fn timed_out(&self) -> bool {
+        self.remaining()
+            .is_some_and(|remaining| remaining.is_zero())
+    }
+}
+
+/// Shuts the connection to the server down once the request_timeout passes, so that whatever
+/// the proxy is waiting on fails. Dropping it disarms it.
+struct Deadline(tokio::task::JoinHandle<()>);
+
+impl Deadline {
+    fn arm(socket: socket2::Socket, after: Duration) -> Self {
+        Self(tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            info!("request_timeout passed, closing the connection to the server");
+            let _ = socket.shutdown(std::net::Shutdown::Both);
+        }))
+    }
+
+    // a duplicate of the socket pingora is using, it stays valid however long pingora keeps
+    // its own open
+    #[cfg(unix)]
+    fn socket(fd: std::os::unix::io::RawFd) -> std::io::Result<socket2::Socket> {
+        // SAFETY: pingora hands out the fd of a connection it holds open during the call
+        let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+        Ok(fd.try_clone_to_owned()?.into())
+    }
+
+    #[cfg(windows)]
+    fn socket(sock: std::os::windows::io::RawSocket) -> std::io::Result<socket2::Socket> {
+        // SAFETY: pingora hands out the socket of a connection it holds open during the call
+        let sock = unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(sock) };
+        Ok(sock.try_clone_to_owned()?.into())
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// turns a retryable status into an error so that pingora tries again
+const RETRYABLE_STATUS: ErrorType = ErrorType::Custom("retryable status");
+
+// the per try timeout and what is left of the timeout budget cap every step of an attempt,
+// pingora applies the read and write timeouts to each read and write
+fn set_timeouts(
+    peer: &mut HttpPeer,
+    timeouts: &UpstreamTimeouts,
+    per_try_timeout: Option<Duration>,
+    remaining: Option<Duration>,
+) {
+    let cap = |timeout: Option<Duration>| {
+        [timeout, per_try_timeout, remaining]
+            .into_iter()
+            .flatten()
+            .min()
+    };
+    peer.options.connection_timeout = cap(timeouts.connect_timeout);
+    peer.options.total_connection_timeout = cap(timeouts.total_connection_timeout);
+    peer.options.read_timeout = cap(timeouts.read_timeout);
+    peer.options.write_timeout = cap(timeouts.write_timeout);
+    peer.options.idle_timeout = timeouts.idle_timeout;
+}
+
+// answers a plain http request with a redirect to its `https://` URL
+async fn redirect_to_https(
+    session: &mut Session,
+    host: &str,
+    redirect: RedirectToHttpsResolved,
+) -> Result<()> {
+    // without a Host there is no URL to redirect to
+    if host.is_empty() {
+        return session.respond_error(400).await;
+    }
+    let path = session
+        .req_header()
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let location = https_location(host, redirect.port, path);
+    let mut resp = ResponseHeader::build(redirect.status, Some(2))?;
+    resp.insert_header(header::LOCATION, location)?;
+    resp.insert_header(header::CONTENT_LENGTH, "0")?;
+    session.write_response_header(Box::new(resp), true).await
 }
 
 impl SimpleProxy {
@@ -38,9 +244,20 @@ impl SimpleProxy {
         Ok(Self {
             config,
             route_table,
+            downstreams: Downstreams::default(),
+            listener: None,
         })
     }
 
+    /// The proxy serving `listener`, sharing the config, routes and downstream connections
+    /// with `self`.
+    pub fn for_listener(&self, listener: &ListenerConfigResolved) -> Self {
+        Self {
+            listener: Some(listener.clone()),
+            ..self.clone()
+        }
+    }
+
     pub fn config(&self) -> &ProxyConfig {
         &self.config
     }
@@ -48,6 +265,10 @@ impl SimpleProxy {
     pub fn route_table(&self) -> &RouteTable {
         &self.route_table
     }
+
+    pub fn downstreams(&self) -> &Downstreams {
+        &self.downstreams
+    }
 }
 
 #[async_trait]
@@ -65,11 +286,72 @@ impl ProxyHttp for SimpleProxy {
             session.req_header().headers
         );
 
+        ctx.started = Some(Instant::now());
+        ctx.request_id = format!("{:032x}", rand::random::<u128>());
+        ctx.downstream = self.downstreams.get(session);
+        ctx.tls = match ctx.downstream.as_deref() {
+            Some(downstream) => downstream.tls.is_some(),
+            None => session
+                .digest()
+                .is_some_and(|digest| digest.ssl_digest.is_some()),
+        };
+        ctx.client_addr = get_client_addr(session, ctx.downstream.as_deref());
+        ctx.server_addr = get_server_addr(session, ctx.downstream.as_deref());
+        let peer_ip = ctx.client_addr.map(|addr| addr.ip());
+        ctx.client_ip = match (&self.config.get().global.real_ip, peer_ip) {
+            (Some(real_ip), Some(peer)) => Some(get_real_ip(session.req_header(), peer, real_ip)),
+            _ => peer_ip,
+        };
+
         // route to the correct upstream
         let (host, port) = get_session_host_port(session);
         ctx.host = host.to_string();
         ctx.port = port;
-        ctx.entry = self.route_table.pin_owned().get(host).cloned();
+        if let Some(tls) = ctx.downstream_tls()
+            && !tls.serves(host)
+        {
+            session.respond_error(421).await?;
+            return Ok(true);
+        }
+        let listener = self.listener.as_ref();
+        ctx.entry = self.route_table.lookup(
+            host,
+            session.req_header(),
+            listener.map(|listener| listener.name.as_str()),
+        );
+
+        // plain http requests of servers or listeners that only serve https
+        let redirect = ctx
+            .entry
+            .as_ref()
+            .and_then(|entry| entry.redirect_to_https)
+            .or_else(|| listener.and_then(|listener| listener.redirect_to_https));
+        if !ctx.tls
+            && let Some(redirect) = redirect
+        {
+            redirect_to_https(session, &ctx.host, redirect).await?;
+            return Ok(true);
+        }
+        if let Some(key) = ctx
+            .entry
+            .as_ref()
+            .and_then(|entry| entry.config.hash_key.as_ref())
+        {
+            ctx.hash_key = get_hash_key(session, key, ctx.client_ip);
+        }
+
+        // clients without a certificate get this far when the listener's client_auth is `optional`
+        if ctx
+            .entry
+            .as_ref()
+            .is_some_and(|entry| entry.client_auth == ClientAuth::Required)
+            && ctx
+                .downstream_tls()
+                .is_none_or(|tls| tls.client_cert.is_none())
+        {
+            session.respond_error(403).await?;
+            return Ok(true);
+        }
 
         Ok(false)
     }
@@ -95,10 +377,64 @@ impl ProxyHttp for SimpleProxy {
             ));
         };
 
-        match server.select(&ctx.host) {
+        // a retry selects again, give the previous backend back first
+        if let Some(backend) = ctx.backend.take() {
+            server.release(&backend);
+        }
+        let remaining = ctx.remaining();
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            return Err(Error::explain(
+                HTTPStatus(504),
+                "request_timeout passed before connecting",
+            ));
+        }
+        if ctx.tries.is_empty() {
+            server.upstream.retry_budget().request();
+        }
+        match server.select(&ctx.hash_key, &ctx.tries) {
             Some(backend) => {
                 info!("upstream_peer, backend: {:?}", backend);
-                let peer = HttpPeer::new(backend, server.tls, ctx.host.clone());
+                ctx.backend = Some(backend.clone());
+                ctx.tries.push(backend.clone());
+                let uds = backend
+                    .addr
+                    .as_unix()
+                    .and_then(|addr| addr.as_pathname())
+                    .map(|path| path.to_string_lossy().into_owned());
+                let mut peer = match uds {
+                    Some(path) => HttpPeer::new_uds(&path, server.tls, ctx.host.clone())?,
+                    None => HttpPeer::new(backend, server.tls, ctx.host.clone()),
+                };
+                let timeouts = &server.config.timeouts;
+                set_timeouts(
+                    &mut peer,
+                    timeouts,
+                    server.config.retry.per_try_timeout,
+                    remaining,
+                );
+                let proxy_protocol = server.config.proxy_protocol.map(|version| {
+                    let addrs =
+                        ctx.client_addr
+                            .zip(ctx.server_addr)
+                            .map(|(source, destination)| ProxyHeader {
+                                source,
+                                destination,
+                            });
+                    // a connection carries the header of one downstream connection, keep it
+                    // out of the pool of the others
+                    let mut hasher = DefaultHasher::new();
+                    addrs.hash(&mut hasher);
+                    peer.group_key = hasher.finish();
+                    ProxyProtocolConnector::new(version, addrs, (&peer.options).into())
+                });
+                match server.upstream.tls() {
+                    Some(tls) => tls.apply(&mut peer, proxy_protocol),
+                    None => {
+                        if let Some(connector) = proxy_protocol {
+                            peer.options.custom_l4 = Some(Arc::new(connector));
+                        }
+                    }
+                }
                 Ok(Box::new(peer))
             }
             None => Err(Error::create(
@@ -242,14 +578,51 @@ impl ProxyHttp for SimpleProxy {
         &self,
         session: &mut Session,
         upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
         info!(
             "upstream_request_filter, request headers: {:?}, upstream request headers: {:?}",
             session.req_header().headers,
             upstream_request.headers
         );
-        upstream_request.insert_header("user-agent", "simple-proxy-agent")?;
+        if let Some(entry) = ctx.entry.as_ref() {
+            let host = session
+                .get_header(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .or_else(|| session.req_header().uri.authority().map(|a| a.as_str()));
+            let forwarded = ForwardedRequest {
+                client_ip: ctx.client_ip,
+                peer_ip: ctx.client_addr.map(|addr| addr.ip()),
+                tls: ctx.tls,
+                host,
+                port: ctx.server_addr.map_or(ctx.port, |addr| addr.port()),
+            };
+            forwarded.apply(&entry.forwarded_headers, upstream_request)?;
+            entry
+                .request_headers
+                .apply(upstream_request, &ctx.header_vars())?;
+        }
+
+        // the identity headers are only ever set by the proxy, whatever the client sent
+        if let Some(tls) = self.config.get().global.tls.as_ref() {
+            let headers = &tls.client_identity_headers;
+            for name in [&headers.subject, &headers.san, &headers.fingerprint] {
+                upstream_request.remove_header(name.as_str());
+            }
+            let identity = ctx
+                .downstream_tls()
+                .and_then(|tls| tls.client_cert.as_ref());
+            if let (Some(entry), Some(identity)) = (ctx.entry.as_ref(), identity)
+                && entry.client_auth != ClientAuth::None
+            {
+                upstream_request.insert_header(headers.subject.clone(), &identity.subject)?;
+                if !identity.san.is_empty() {
+                    upstream_request.insert_header(headers.san.clone(), identity.san.join(", "))?;
+                }
+                upstream_request
+                    .insert_header(headers.fingerprint.clone(), &identity.fingerprint)?;
+            }
+        }
         Ok(())
     }
 
@@ -257,29 +630,56 @@ impl ProxyHttp for SimpleProxy {
         &self,
         session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) {
         info!(
             "upstream_response_filter, request headers: {:?}, upstream response headers: {:?}",
             session.req_header().headers,
             upstream_response.headers
         );
-        upstream_response
-            .insert_header("user-agent", "simple-proxy-agent")
-            .unwrap();
+        ctx.report(if upstream_response.status.is_server_error() {
+            Outcome::ServerError
+        } else {
+            Outcome::Success
+        });
     }
 
     async fn response_filter(
         &self,
         session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
         info!(
             "response_filter, request headers: {:?}, upstream response headers: {:?}",
             session.req_header().headers,
             upstream_response.headers
         );
+        let status = upstream_response.status.as_u16();
+        if ctx
+            .entry
+            .as_ref()
+            .is_some_and(|entry| entry.config.retry.statuses.contains(&status))
+            && ctx.should_retry(session, None)
+        {
+            let mut e = Error::explain(RETRYABLE_STATUS, format!("status {}", status));
+            e.set_retry(true);
+            return Err(e);
+        }
+        ctx.responded = true;
+
+        // browsers only take the header from https responses
+        if ctx.tls
+            && let Some(hsts) = ctx.entry.as_ref().and_then(|entry| entry.hsts.as_ref())
+        {
+            upstream_response
+                .insert_header(header::STRICT_TRANSPORT_SECURITY, hsts.header_value())?;
+        }
+        if let Some(entry) = ctx.entry.as_ref() {
+            entry
+                .response_headers
+                .apply(upstream_response, &ctx.header_vars())?;
+        }
         Ok(())
     }
 
@@ -288,7 +688,7 @@ impl ProxyHttp for SimpleProxy {
         session: &mut Session,
         body: &mut Option<Bytes>,
         end_of_stream: bool,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) {
         info!(
             "upstream_response_body_filter, request headers: {:?}, body length: {:?}, end_of_stream: {}",
@@ -296,6 +696,10 @@ impl ProxyHttp for SimpleProxy {
             body.as_ref().map(|b| b.len()),
             end_of_stream
         );
+        // the server is done, its connection may go back to the pool
+        if end_of_stream {
+            ctx.deadline = None;
+        }
     }
 
     fn upstream_response_trailer_filter(
@@ -342,12 +746,16 @@ impl ProxyHttp for SimpleProxy {
         Ok(None)
     }
 
-    async fn logging(&self, session: &mut Session, e: Option<&Error>, _ctx: &mut Self::CTX) {
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
         info!(
-            "logging, request headers: {:?}, error: {:?}",
+            "logging, client: {:?}, request headers: {:?}, error: {:?}",
+            ctx.client_ip,
             session.req_header().headers,
             e
         );
+        if let (Some(entry), Some(backend)) = (ctx.entry.as_ref(), ctx.backend.take()) {
+            entry.release(&backend);
+        }
     }
 
     fn suppress_error_log(&self, session: &Session, _ctx: &Self::CTX, error: &Error) -> bool {
@@ -364,7 +772,7 @@ impl ProxyHttp for SimpleProxy {
         peer: &HttpPeer,
         session: &mut Session,
         e: Box<Error>,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
         client_reused: bool,
     ) -> Box<Error> {
         info!(
@@ -374,9 +782,27 @@ impl ProxyHttp for SimpleProxy {
             e,
             client_reused
         );
+        // already decided by response_filter, and reported as a 5xx
+        if e.etype() == &RETRYABLE_STATUS {
+            return e;
+        }
+        // a client going away mid-response or a request cut off by its request_timeout says
+        // nothing about the server
+        if e.esource() == &ErrorSource::Upstream && !ctx.timed_out() {
+            ctx.report(Outcome::Error);
+        }
         let mut e = e.more_context(format!("Peer: {}", peer));
-        e.retry
-            .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        let failure = match e.etype() {
+            ReadTimedout | WriteTimedout => RetryOn::Timeout,
+            _ => RetryOn::Error,
+        };
+        // another server does not mend a broken client connection
+        if e.esource() == &ErrorSource::Upstream && ctx.should_retry(session, Some(failure)) {
+            e.set_retry(true);
+        } else {
+            e.retry
+                .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        }
         e
     }
 
@@ -384,8 +810,8 @@ impl ProxyHttp for SimpleProxy {
         &self,
         session: &mut Session,
         peer: &HttpPeer,
-        _ctx: &mut Self::CTX,
-        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
     ) -> Box<Error> {
         info!(
             "fail_to_connect, request headers: {:?}, peer: {:?}, error: {:?}",
@@ -393,10 +819,14 @@ impl ProxyHttp for SimpleProxy {
             peer,
             e
         );
+        ctx.report(Outcome::ConnectFailure);
+        if ctx.should_retry(session, Some(RetryOn::ConnectFailure)) {
+            e.set_retry(true);
+        }
         e
     }
 
-    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, _ctx: &mut Self::CTX) -> u16 {
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16 {
         info!(
             "fail_to_proxy, request headers: {:?}, error: {:?}",
             session.req_header().headers,
@@ -405,6 +835,13 @@ impl ProxyHttp for SimpleProxy {
         let server_session = session.as_mut();
         let code = match e.etype() {
             HTTPStatus(code) => *code,
+            // the connection was cut by the request_timeout, whatever the error says
+            _ if e.esource() == &ErrorSource::Upstream && ctx.timed_out() => 504,
+            ConnectTimedout | TLSHandshakeTimedout | ReadTimedout | WriteTimedout
+                if e.esource() == &ErrorSource::Upstream =>
+            {
+                504
+            }
             _ => {
                 match e.esource() {
                     ErrorSource::Upstream => 502,
@@ -446,10 +883,10 @@ impl ProxyHttp for SimpleProxy {
         session: &mut Session,
         reused: bool,
         peer: &HttpPeer,
-        #[cfg(unix)] _fd: std::os::unix::io::RawFd,
-        #[cfg(windows)] _sock: std::os::windows::io::RawSocket,
+        #[cfg(unix)] fd: std::os::unix::io::RawFd,
+        #[cfg(windows)] sock: std::os::windows::io::RawSocket,
         _digest: Option<&Digest>,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()> {
         info!(
             "connected_to_upstream, request headers: {:?}, reused: {}, peer: {:?}",
@@ -457,6 +894,20 @@ impl ProxyHttp for SimpleProxy {
             reused,
             peer
         );
+        // the previous attempt's connection is done with
+        ctx.deadline = None;
+        // an h2 connection carries the requests of other clients too, cutting it is not an
+        // option: only the capped timeouts apply there
+        if let Some(remaining) = ctx.remaining()
+            && peer.options.alpn.get_max_http_version() == 1
+        {
+            #[cfg(unix)]
+            let socket = Deadline::socket(fd);
+            #[cfg(windows)]
+            let socket = Deadline::socket(sock);
+            let socket = socket.or_err(InternalError, "failed to arm the request_timeout")?;
+            ctx.deadline = Some(Deadline::arm(socket, remaining));
+        }
         Ok(())
     }
 
@@ -491,3 +942,190 @@ impl ProxyHttp for SimpleProxy {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::{SimpleProxyConfig, SimpleProxyConfigResolved};
+    use pingora::{
+        connectors::http::Connector,
+        protocols::{UniqueID, http::client::HttpSession},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // a server that waits `delay` before each of the `chunks` of its response
+    async fn server(chunks: Vec<&'static str>, delay: Duration) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await?;
+            for chunk in chunks {
+                tokio::time::sleep(delay).await;
+                stream.write_all(chunk.as_bytes()).await?;
+            }
+            anyhow::Ok(())
+        });
+        Ok(addr)
+    }
+
+    // read the response header the way pingora's proxy does, cut off `deadline` from now
+    async fn read_response(peer: &HttpPeer, deadline: Option<Duration>) -> Result<u16> {
+        let (session, _) = Connector::new(None).get_http_session(peer).await?;
+        let HttpSession::H1(mut session) = session else {
+            panic!("expected an http/1 session");
+        };
+        let _deadline = match deadline {
+            Some(deadline) => {
+                let socket = Deadline::socket(session.id()).or_err(InternalError, "dup")?;
+                Some(Deadline::arm(socket, deadline))
+            }
+            None => None,
+        };
+        session.read_timeout = peer.options.read_timeout;
+        session.write_timeout = peer.options.write_timeout;
+        let req = RequestHeader::build("GET", b"/", None)?;
+        session.write_request_header(Box::new(req)).await?;
+        session.read_response().await?;
+        Ok(session.resp_header().map_or(0, |resp| resp.status.as_u16()))
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_request_timeout() -> anyhow::Result<()> {
+        let timeouts = UpstreamTimeouts {
+            connect_timeout: Some(Duration::from_secs(10)),
+            read_timeout: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let remaining = Some(Duration::from_millis(300));
+
+        // the timeouts of an attempt are capped to what is left of the request_timeout
+        let addr = server(vec!["HTTP/1.1 200 OK\r\n\r\n"], Duration::from_secs(2)).await?;
+        let mut peer = HttpPeer::new(addr, false, "acme.com".to_string());
+        set_timeouts(&mut peer, &timeouts, None, remaining);
+        assert_eq!(peer.options.connection_timeout, remaining);
+        assert_eq!(peer.options.read_timeout, remaining);
+        let started = Instant::now();
+        let e = read_response(&peer, None).await.unwrap_err();
+        assert_eq!(e.etype(), &ErrorType::ReadTimedout);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // a server trickling its response within the read timeout is cut off at the deadline
+        let chunks = vec![
+            "HTTP/1.1 200 OK\r\n",
+            "content-length: 0\r\n",
+            "x-a: 1\r\n",
+            "\r\n",
+        ];
+        let addr = server(chunks.clone(), Duration::from_millis(150)).await?;
+        let mut peer = HttpPeer::new(addr, false, "acme.com".to_string());
+        set_timeouts(&mut peer, &timeouts, None, remaining);
+        let started = Instant::now();
+        assert!(read_response(&peer, remaining).await.is_err());
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(300) && elapsed < Duration::from_millis(500));
+
+        // one answering within the deadline is not affected by it
+        let addr = server(chunks, Duration::from_millis(10)).await?;
+        let peer = HttpPeer::new(addr, false, "acme.com".to_string());
+        assert_eq!(read_response(&peer, remaining).await?, 200);
+        Ok(())
+    }
+
+    fn proxy(yaml: &str) -> anyhow::Result<SimpleProxy> {
+        let config: SimpleProxyConfig = yaml.parse()?;
+        let config = SimpleProxyConfigResolved::try_from(config)?;
+        SimpleProxy::try_new(ProxyConfig::new(config))
+    }
+
+    // fail a request to acme.com with `e` while proxying it to the server selected for it
+    async fn error_while_proxy(
+        proxy: &SimpleProxy,
+        e: Box<Error>,
+    ) -> anyhow::Result<(RouteEntry, Box<Error>)> {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: acme.com\r\n\r\n")
+            .await?;
+        let mut session = Session::new_h1(Box::new(server));
+        assert!(session.read_request().await?);
+        let entry = proxy
+            .route_table
+            .lookup("acme.com", session.req_header(), None)
+            .expect("route");
+        let mut ctx = ProxyContext {
+            backend: entry.select(b"", &[]),
+            entry: Some(entry.clone()),
+            ..Default::default()
+        };
+        let peer = HttpPeer::new("127.0.0.1:3001", false, "acme.com".to_string());
+        let e = proxy.error_while_proxy(&peer, &mut session, e, &mut ctx, false);
+        Ok((entry, e))
+    }
+
+    #[tokio::test]
+    async fn test_error_while_proxy_outliers() -> anyhow::Result<()> {
+        let proxy = proxy(
+            r#"
+global:
+  port: 3000
+servers:
+  - server_name: [acme.com]
+    upstream: web_servers
+upstreams:
+  - name: web_servers
+    servers: ["127.0.0.1:3001"]
+    outlier_detection:
+      error_rate: 0.5
+      min_requests: 1
+      max_ejection_percent: 100
+"#,
+        )?;
+
+        // the client going away mid-response leaves the server in
+        let e = Error::explain(WriteError, "client gone").into_down();
+        let (entry, _) = error_while_proxy(&proxy, e).await?;
+        assert!(entry.select(b"", &[]).is_some());
+        // the server breaking the connection ejects it
+        let e = Error::explain(ReadError, "connection reset").into_up();
+        let (entry, _) = error_while_proxy(&proxy, e).await?;
+        assert!(entry.select(b"", &[]).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_error_while_proxy_retry() -> anyhow::Result<()> {
+        let proxy = proxy(
+            r#"
+global:
+  port: 3000
+servers:
+  - server_name: [acme.com]
+    upstream: web_servers
+upstreams:
+  - name: web_servers
+    servers: ["127.0.0.1:3001", "127.0.0.1:3002"]
+    retry:
+      attempts: 2
+      retry_on: [error, timeout]
+      idempotent_only: false
+"#,
+        )?;
+
+        let e = Error::explain(ReadError, "connection reset").into_up();
+        assert!(error_while_proxy(&proxy, e).await?.1.retry());
+        let e = Error::explain(ReadTimedout, "server stalled").into_up();
+        assert!(error_while_proxy(&proxy, e).await?.1.retry());
+        // failures of the client's connection are not retried on another server
+        let e = Error::explain(WriteError, "client gone").into_down();
+        assert!(!error_while_proxy(&proxy, e).await?.1.retry());
+        let e = Error::explain(ReadTimedout, "client stalled").into_down();
+        assert!(!error_while_proxy(&proxy, e).await?.1.retry());
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use pingora::{
+    tls::{load_certs_and_key_files, load_platform_certs_incl_env_into_store},
+    upstreams::peer::{ALPN, HttpPeer, Scheme},
+    utils::tls::CertKey,
+};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{CryptoProvider, aws_lc_rs, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+};
+use tokio::net::UnixStream;
+use tokio_rustls::TlsConnector;
+
+use crate::{
+    conf::{Alpn, UpstreamTlsResolved},
+    proxy::proxy_protocol::{ProxyProtocolConnector, RawStream},
+};
+
+/// Client side TLS of the connections to the servers of an upstream, loaded from its
+/// `upstream_tls` block.
+pub(crate) struct UpstreamTls {
+    config: UpstreamTlsResolved,
+    // DER of the certificate chain and of the key, health checks build their own [CertKey]
+    client_cert: Option<(Vec<Vec<u8>>, Vec<u8>)>,
+    client_cert_key: Option<Arc<CertKey>>,
+    // pingora's connector verifies every server against the root store of `global.tls.ca`,
+    // an upstream with a `ca` of its own or with verification turned off is connected to with
+    // this one instead
+    client_config: Option<Arc<ClientConfig>>,
+}
+
+impl UpstreamTls {
+    pub fn new(config: &UpstreamTlsResolved) -> anyhow::Result<Self> {
+        let client_cert = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let (certs, key) = load_certs_and_key_files(cert, key)
+                    .with_context(|| format!("failed to load client certificate {}", cert))?
+                    .with_context(|| format!("no certificate or key in {} and {}", cert, key))?;
+                let certs = certs.iter().map(|cert| cert.to_vec()).collect();
+                Some((certs, key.secret_der().to_vec()))
+            }
+            _ => None,
+        };
+        let client_config = if config.ca.is_some() || !config.verify_cert || !config.verify_hostname
+        {
+            Some(Arc::new(client_config(config, client_cert.as_ref())?))
+        } else {
+            None
+        };
+        Ok(Self {
+            config: config.clone(),
+            client_cert_key: client_cert
+                .clone()
+                .map(|(certs, key)| Arc::new(CertKey::new(certs, key))),
+            client_cert,
+            client_config,
+        })
+    }
+
+    /// Set up a peer the proxy connects to. `l4` is the connector of upstreams with
+    /// `proxy_protocol`.
+    pub fn apply(&self, peer: &mut HttpPeer, l4: Option<ProxyProtocolConnector>) {
+        if let Some(sni) = &self.config.sni {
+            peer.sni = sni.clone();
+        }
+        peer.options.alpn = match self.config.alpn {
+            Alpn::H1 => ALPN::H1,
+            Alpn::H2 => ALPN::H2,
+            Alpn::H2H1 => ALPN::H2H1,
+        };
+        let Some(client_config) = &self.client_config else {
+            peer.client_cert_key = self.client_cert_key.clone();
+            if let Some(l4) = l4 {
+                peer.options.custom_l4 = Some(Arc::new(l4));
+            }
+            return;
+        };
+        let l4 = l4.unwrap_or_else(|| ProxyProtocolConnector::direct((&peer.options).into()));
+        let connector = UpstreamTlsConnector {
+            config: client_config.clone(),
+            sni: peer.sni.clone(),
+            timeout: peer.options.connection_timeout,
+            l4,
+        };
+        // pingora speaks plain http over the connector, the peers of other upstreams to the
+        // same address must not share its connections
+        peer.scheme = Scheme::HTTP;
+        peer.options.custom_l4 = Some(Arc::new(connector));
+        let mut hasher = DefaultHasher::new();
+        (peer.group_key, &self.config).hash(&mut hasher);
+        peer.group_key = hasher.finish();
+    }
+
+    /// Set up the peer of an http health check, pingora's load balancing has types of its own.
+    pub fn apply_health_check(
+        &self,
+        peer: &mut pingora_core::upstreams::peer::HttpPeer,
+        l4: Option<ProxyProtocolConnector>,
+    ) {
+        use pingora_core::upstreams::peer::{ALPN, Scheme};
+
+        if let Some(sni) = &self.config.sni {
+            peer.sni = sni.clone();
+        }
+        // probes are plain requests, there is no need to negotiate h2
+        peer.options.alpn = ALPN::H1;
+        let Some(client_config) = &self.client_config else {
+            peer.client_cert_key = self
+                .client_cert
+                .clone()
+                .map(|(certs, key)| Arc::new(pingora_core::utils::tls::CertKey::new(certs, key)));
+            if let Some(l4) = l4 {
+                peer.options.custom_l4 = Some(Arc::new(l4));
+            }
+            return;
+        };
+        let l4 = l4.unwrap_or_else(|| ProxyProtocolConnector::direct((&peer.options).into()));
+        let mut config = ClientConfig::clone(client_config);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let connector = UpstreamTlsConnector {
+            config: Arc::new(config),
+            sni: peer.sni.clone(),
+            timeout: peer.options.connection_timeout,
+            l4,
+        };
+        peer.scheme = Scheme::HTTP;
+        peer.options.custom_l4 = Some(Arc::new(connector));
+    }
+}
+
+fn client_config(
+    config: &UpstreamTlsResolved,
+    client_cert: Option<&(Vec<Vec<u8>>, Vec<u8>)>,
+) -> anyhow::Result<ClientConfig> {
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let webpki = if config.verify_cert {
+        let mut roots = RootCertStore::empty();
+        match &config.ca {
+            Some(ca) => {
+                let pem = std::fs::read(ca).with_context(|| format!("failed to read ca {}", ca))?;
+                for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+                    roots
+                        .add(cert?)
+                        .with_context(|| format!("invalid certificate in {}", ca))?;
+                }
+            }
+            None => load_platform_certs_incl_env_into_store(&mut roots)
+                .context("failed to load the platform's root certificates")?,
+        }
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .context("invalid root certificates")?;
+        Some(verifier)
+    } else {
+        None
+    };
+    let verifier = Verifier {
+        webpki,
+        verify_hostname: config.verify_hostname,
+        provider: provider.clone(),
+    };
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut client_config = match client_cert {
+        Some((certs, key)) => {
+            let certs = certs.iter().cloned().map(CertificateDer::from).collect();
+            let key = PrivateKeyDer::try_from(key.clone())
+                .map_err(|e| anyhow::anyhow!("invalid client key: {}", e))?;
+            builder.with_client_auth_cert(certs, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    // pingora cannot tell which protocol was negotiated over the connector, `h2h1` falls back
+    // to http/1.1 rather than leave the choice to the server
+    client_config.alpn_protocols = match config.alpn {
+        Alpn::H2 => vec![b"h2".to_vec()],
+        Alpn::H1 | Alpn::H2H1 => vec![b"http/1.1".to_vec()],
+    };
+    Ok(client_config)
+}
+
+/// Verifies servers against the root store of an upstream, skipping what it turned off.
+#[derive(Debug)]
+struct Verifier {
+    // `None` when certificates are not verified at all
+    webpki: Option<Arc<WebPkiServerVerifier>>,
+    verify_hostname: bool,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(webpki) = &self.webpki else {
+            return Ok(ServerCertVerified::assertion());
+        };
+        // the name is only checked once the chain is valid
+        match webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. },
+            )) if !self.verify_hostname => Ok(ServerCertVerified::assertion()),
+            verified => verified,
+        }
+    }
+
+    // the handshake is still signed by the key of the certificate, whether it is trusted or not
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Connects to the servers of an upstream with TLS of its own. The TLS session is run by a
+/// task of its own and pingora speaks plain http over the other end of a socket pair.
+pub(crate) struct UpstreamTlsConnector {
+    config: Arc<ClientConfig>,
+    sni: String,
+    // of the handshake, like pingora's connector
+    timeout: Option<Duration>,
+    l4: ProxyProtocolConnector,
+}
+
+// what failed, the connection under TLS or the handshake
+enum Failure {
+    Connect(io::Error),
+    Handshake(io::Error),
+}
+
+impl UpstreamTlsConnector {
+    async fn connect_tls(
+        &self,
+        inet: Option<std::net::SocketAddr>,
+        unix: Option<&Path>,
+    ) -> Result<UnixStream, Failure> {
+        let stream = self
+            .l4
+            .connect_raw(inet, unix)
+            .await
+            .map_err(Failure::Connect)?;
+        // pingora only sets it on the end of the socket pair it gets
+        if let RawStream::Tcp(tcp) = &stream {
+            tcp.set_nodelay(true).map_err(Failure::Connect)?;
+        }
+        let stream = pingora::protocols::l4::stream::Stream::from(stream);
+        let server_name = ServerName::try_from(self.sni.clone())
+            .map_err(|e| Failure::Handshake(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let handshake = TlsConnector::from(self.config.clone()).connect(server_name, stream);
+        let mut tls = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, handshake)
+                .await
+                .map_err(|_| {
+                    Failure::Handshake(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "tls handshake timed out",
+                    ))
+                })?,
+            None => handshake.await,
+        }
+        .map_err(Failure::Handshake)?;
+        let (proxy, mut relay) = UnixStream::pair().map_err(Failure::Connect)?;
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut relay, &mut tls).await;
+        });
+        Ok(proxy)
+    }
+}
+
+#[async_trait]
+impl pingora::connectors::L4Connect for UpstreamTlsConnector {
+    async fn connect(
+        &self,
+        addr: &pingora::protocols::l4::socket::SocketAddr,
+    ) -> pingora::Result<pingora::protocols::l4::stream::Stream> {
+        use pingora::{
+            Error,
+            ErrorType::{ConnectError, ConnectTimedout, TLSHandshakeFailure, TLSHandshakeTimedout},
+        };
+
+        let stream = self
+            .connect_tls(
+                addr.as_inet().copied(),
+                addr.as_unix().and_then(|a| a.as_pathname()),
+            )
+            .await
+            .map_err(|e| {
+                let (etype, e) = match e {
+                    Failure::Connect(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        (ConnectTimedout, e)
+                    }
+                    Failure::Connect(e) => (ConnectError, e),
+                    Failure::Handshake(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        (TLSHandshakeTimedout, e)
+                    }
+                    Failure::Handshake(e) => (TLSHandshakeFailure, e),
+                };
+                Error::because(etype, format!("failed to connect to {}", addr), e)
+            })?;
+        Ok(stream.into())
+    }
+}
+
+// health checks go through pingora's load balancing, which has types of its own
+#[async_trait]
+impl pingora_core::connectors::L4Connect for UpstreamTlsConnector {
+    async fn connect(
+        &self,
+        addr: &pingora_core::protocols::l4::socket::SocketAddr,
+    ) -> pingora_error::Result<pingora_core::protocols::l4::stream::Stream> {
+        use pingora_error::{
+            Error,
+            ErrorType::{ConnectError, ConnectTimedout, TLSHandshakeFailure, TLSHandshakeTimedout},
+        };
+
+        let stream = self
+            .connect_tls(
+                addr.as_inet().copied(),
+                addr.as_unix().and_then(|a| a.as_pathname()),
+            )
+            .await
+            .map_err(|e| {
+                let (etype, e) = match e {
+                    Failure::Connect(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        (ConnectTimedout, e)
+                    }
+                    Failure::Connect(e) => (ConnectError, e),
+                    Failure::Handshake(e) if e.kind() == io::ErrorKind::TimedOut => {
+                        (TLSHandshakeTimedout, e)
+                    }
+                    Failure::Handshake(e) => (TLSHandshakeFailure, e),
+                };
+                Error::because(etype, format!("failed to connect to {}", addr), e)
+            })?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::UpstreamTlsConfig;
+    use pingora::{
+        connectors::http::Connector, http::RequestHeader, protocols::http::client::HttpSession,
+    };
+    use rustls::ServerConfig;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    // a server with the certificate of the private CA, answering every connection with a 200
+    async fn backend() -> anyhow::Result<std::net::SocketAddr> {
+        let (certs, key) = load_certs_and_key_files(
+            "./fixtures/certs/backend.crt",
+            "./fixtures/certs/backend.key",
+        )?
+        .context("no certificate")?;
+        let config = ServerConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let mut stream = acceptor.accept(stream).await?;
+                    let mut buf = [0; 1024];
+                    let _ = stream.read(&mut buf).await?;
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await?;
+                    anyhow::Ok(())
+                });
+            }
+        });
+        Ok(addr)
+    }
+
+    // the status of a request to `addr` as `sni` through the upstream's `upstream_tls`
+    async fn request(
+        addr: std::net::SocketAddr,
+        sni: &str,
+        upstream_tls: &str,
+    ) -> pingora::Result<u16> {
+        let config: UpstreamTlsConfig = serde_yaml::from_str(upstream_tls).unwrap();
+        let config = UpstreamTlsResolved::try_from_with_location(&config, "upstream_tls").unwrap();
+        let tls = UpstreamTls::new(&config).unwrap();
+        let mut peer = HttpPeer::new(addr, true, sni.to_string());
+        tls.apply(&mut peer, None);
+        let (session, _) = Connector::new(None).get_http_session(&peer).await?;
+        let HttpSession::H1(mut session) = session else {
+            panic!("expected an http/1 session");
+        };
+        let req = RequestHeader::build("GET", b"/", None)?;
+        session.write_request_header(Box::new(req)).await?;
+        session.read_response().await?;
+        Ok(session.resp_header().map_or(0, |resp| resp.status.as_u16()))
+    }
+
+    #[tokio::test]
+    async fn test_upstream_ca() -> anyhow::Result<()> {
+        let addr = backend().await?;
+        let ca = "{ca: ./fixtures/certs/ca.crt}";
+        assert_eq!(request(addr, "api.acme.com", ca).await?, 200);
+
+        // the certificate is not valid for the name
+        let e = request(addr, "globex.com", ca).await.unwrap_err();
+        assert_eq!(e.etype(), &pingora::ErrorType::TLSHandshakeFailure);
+        // unless the name is not verified
+        let upstream_tls = "{ca: ./fixtures/certs/ca.crt, verify_hostname: false}";
+        assert_eq!(request(addr, "globex.com", upstream_tls).await?, 200);
+        // the chain still is
+        let upstream_tls = "{verify_hostname: false}";
+        let e = request(addr, "acme.com", upstream_tls).await.unwrap_err();
+        assert_eq!(e.etype(), &pingora::ErrorType::TLSHandshakeFailure);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_verify_cert() -> anyhow::Result<()> {
+        let addr = backend().await?;
+        assert_eq!(
+            request(addr, "globex.com", "{verify_cert: false}").await?,
+            200
+        );
+        Ok(())
+    }
+}
@@ -1,5 +1,12 @@
+use std::net::{IpAddr, SocketAddr};
+
 use axum::http;
-use pingora::proxy::Session;
+use pingora::{http::RequestHeader, proxy::Session};
+
+use crate::{
+    conf::{HashKey, RealIpResolved},
+    proxy::listener::Downstream,
+};
 
 pub(crate) fn get_session_host_port(session: &Session) -> (&str, u16) {
     let uri = &session.req_header().uri;
@@ -9,15 +16,217 @@ pub(crate) fn get_session_host_port(session: &Session) -> (&str, u16) {
     };
 
     match session.get_header(http::header::HOST) {
-        Some(host) => {
-            let parts: Vec<&str> = host.to_str().unwrap_or_default().split(':').collect();
-            let host = parts[0];
-            let port = parts[1].parse().unwrap_or(default_port);
-            (host, port)
-        }
+        Some(host) => split_host_port(host.to_str().unwrap_or_default(), default_port),
         None => (
             uri.host().unwrap_or_default(),
             uri.port_u16().unwrap_or(default_port),
         ),
     }
 }
+
+// `host[:port]` of a Host header, IPv6 addresses are in brackets
+fn split_host_port(authority: &str, default_port: u16) -> (&str, u16) {
+    match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().unwrap_or(default_port)),
+        _ => (authority, default_port),
+    }
+}
+
+/// The `https://` URL of a request to `host`, with `port` unless it is the default one.
+pub(crate) fn https_location(host: &str, port: u16, path_and_query: &str) -> String {
+    match port {
+        443 => format!("https://{}{}", host, path_and_query),
+        port => format!("https://{}:{}{}", host, port, path_and_query),
+    }
+}
+
+/// `(name, value)` pairs of all `Cookie` headers of a request.
+pub(crate) fn get_cookies(req: &RequestHeader) -> impl Iterator<Item = (&str, &str)> {
+    req.headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+}
+
+/// The address of the client, connections relayed by a relay listener come from a Unix
+/// socket and carry it in their [Downstream], as told by the PROXY protocol header if the
+/// listener reads one. IPv4 clients of a dual-stack listener show up as IPv4-mapped IPv6
+/// addresses, they are turned back into IPv4 ones. `None` for clients on a unix socket.
+pub(crate) fn get_client_addr(
+    session: &Session,
+    downstream: Option<&Downstream>,
+) -> Option<SocketAddr> {
+    let addr = match downstream {
+        Some(downstream) => downstream.client_addr,
+        None => *session.client_addr()?.as_inet()?,
+    };
+    Some(SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+}
+
+/// The address the client connected to, see [get_client_addr].
+pub(crate) fn get_server_addr(
+    session: &Session,
+    downstream: Option<&Downstream>,
+) -> Option<SocketAddr> {
+    let addr = match downstream {
+        Some(downstream) => downstream.server_addr,
+        None => *session.server_addr()?.as_inet()?,
+    };
+    Some(SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+}
+
+/// The client a request from `peer` was forwarded for, as told by the `real_ip` header. The
+/// header is only looked at on connections from a trusted proxy; the last address of its
+/// list is taken, or with `recursive` the last one that is not a trusted proxy itself. An
+/// element that is not an address stops the search at what was found so far.
+pub(crate) fn get_real_ip(req: &RequestHeader, peer: IpAddr, real_ip: &RealIpResolved) -> IpAddr {
+    if !real_ip.is_trusted(peer) {
+        return peer;
+    }
+    let forwarded = real_ip.header == "forwarded";
+    let hops: Vec<Option<IpAddr>> = req
+        .headers
+        .get_all(real_ip.header.as_str())
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            if forwarded {
+                forwarded_for(element).and_then(parse_node)
+            } else {
+                parse_node(element.trim())
+            }
+        })
+        .collect();
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Some(hop) = hop else { break };
+        client = hop;
+        if !real_ip.recursive || !real_ip.is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+// the `for` parameter of an RFC 7239 `Forwarded` element, unquoted
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        key.eq_ignore_ascii_case("for")
+            .then(|| value.trim_matches('"'))
+    })
+}
+
+// `192.0.2.1`, `2001:db8::1` or either with a port, `[2001:db8::1]:8080`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let ip = match (node.strip_prefix('['), node.split_once(':')) {
+        (Some(rest), _) => rest.split_once(']')?.0,
+        (None, Some((ip, port))) if !port.contains(':') => ip,
+        _ => node,
+    };
+    ip.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+/// The bytes a hashing load balancer selects the backend by. Empty if the request does not
+/// carry the header or cookie, those requests all land on the same backend.
+pub(crate) fn get_hash_key(session: &Session, key: &HashKey, client_ip: Option<IpAddr>) -> Vec<u8> {
+    let req = session.req_header();
+    match key {
+        HashKey::ClientIp => client_ip
+            .map(|ip| ip.to_string().into_bytes())
+            .unwrap_or_default(),
+        HashKey::Uri => req.uri.to_string().into_bytes(),
+        HashKey::Path => req.uri.path().as_bytes().to_vec(),
+        HashKey::Header(name) => req
+            .headers
+            .get(name.as_str())
+            .map(|value| value.as_bytes().to_vec())
+            .unwrap_or_default(),
+        HashKey::Cookie(name) => get_cookies(req)
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_bytes().to_vec())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("acme.com", 80), ("acme.com", 80));
+        assert_eq!(split_host_port("acme.com:8080", 80), ("acme.com", 8080));
+        assert_eq!(split_host_port("[::1]", 443), ("[::1]", 443));
+        assert_eq!(split_host_port("[::1]:8443", 443), ("[::1]", 8443));
+    }
+
+    #[test]
+    fn test_https_location() {
+        assert_eq!(
+            https_location("acme.com", 443, "/a?b=c"),
+            "https://acme.com/a?b=c"
+        );
+        assert_eq!(https_location("[::1]", 8443, "/"), "https://[::1]:8443/");
+    }
+
+    #[test]
+    fn test_get_real_ip() {
+        let real_ip = |header: &str, recursive| RealIpResolved {
+            trusted: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ],
+            header: header.to_string(),
+            recursive,
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-forwarded-for", "192.0.2.1, 198.51.100.1")
+            .unwrap();
+        req.append_header("x-forwarded-for", "10.0.0.2").unwrap();
+        req.append_header(
+            "forwarded",
+            "for=192.0.2.1, for=\"[2001:db8::2]:8080\";proto=https",
+        )
+        .unwrap();
+
+        let xff = real_ip("x-forwarded-for", false);
+        assert_eq!(get_real_ip(&req, ip("10.0.0.1"), &xff), ip("10.0.0.2"));
+        // the header of an untrusted peer is ignored
+        assert_eq!(get_real_ip(&req, ip("192.0.2.9"), &xff), ip("192.0.2.9"));
+
+        let xff = real_ip("x-forwarded-for", true);
+        assert_eq!(get_real_ip(&req, ip("10.0.0.1"), &xff), ip("198.51.100.1"));
+
+        let forwarded = real_ip("forwarded", true);
+        assert_eq!(
+            get_real_ip(&req, ip("10.0.0.1"), &forwarded),
+            ip("192.0.2.1")
+        );
+
+        // only trusted proxies, the first of them is the client
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-forwarded-for", "10.0.0.3, 10.0.0.2")
+            .unwrap();
+        assert_eq!(get_real_ip(&req, ip("10.0.0.1"), &xff), ip("10.0.0.3"));
+
+        // an invalid element stops the search
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("x-forwarded-for", "192.0.2.1, unknown, 10.0.0.2")
+            .unwrap();
+        assert_eq!(get_real_ip(&req, ip("10.0.0.1"), &xff), ip("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_parse_node() {
+        assert_eq!(parse_node("192.0.2.1"), "192.0.2.1".parse().ok());
+        assert_eq!(parse_node("192.0.2.1:8080"), "192.0.2.1".parse().ok());
+        assert_eq!(parse_node("2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_node("[2001:db8::1]:8080"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+}